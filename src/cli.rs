@@ -1,6 +1,11 @@
 use std::path::PathBuf;
 
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::{
+    config::{DEV_PROFILE, RELEASE_PROFILE},
+    lockfile::LockMode,
+};
 
 #[derive(Parser, Debug)]
 pub struct Cli {
@@ -12,11 +17,18 @@ pub struct Cli {
 pub enum Commands {
     New(NewData),
     Brew(BrewData),
-    Pour,
+    Pour(BrewData),
     Add(AddData),
-    Format,
-    Lint,
-    Sip
+    Remove(RemoveData),
+    Format(SelectionData),
+    Lint(LintData),
+    Sip(BrewData),
+    Tree(TreeData),
+    Metadata(MetadataData),
+    Compdb(CompdbData),
+    Matrix(MatrixData),
+    Package(PackageData),
+    Publish(PublishData),
 }
 
 #[derive(Debug, Args)]
@@ -31,18 +43,232 @@ pub struct NewData {
 
 #[derive(Debug, Args, Clone)]
 pub struct BrewData {
-    #[arg(long, default_value_t = false)]
+    /// Shorthand for --profile release
+    #[arg(long, default_value_t = false, conflicts_with_all = ["debug", "profile"])]
     pub release: bool,
-    #[arg(long, default_value_t = false)]
+    /// Shorthand for --profile dev
+    #[arg(long, default_value_t = false, conflicts_with = "profile")]
     pub debug: bool,
+    /// The [profile.<name>] to build with, defaults to dev
+    #[arg(long)]
+    pub profile: Option<String>,
+    /// The C compiler to build with, e.g. tcc, gcc or clang. Overrides CC and [toolchain]
+    #[arg(long)]
+    pub compiler: Option<String>,
+    /// Where to put build artifacts. Overrides TPOT_TARGET_DIR, defaults to target
+    #[arg(long)]
+    pub target_dir: Option<PathBuf>,
+    /// How many compiler processes to run at once, defaults to the number of CPUs
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+    #[command(flatten)]
+    pub selection: SelectionData,
+    #[command(flatten)]
+    pub features: FeaturesData,
+    #[command(flatten)]
+    pub lock: LockData,
+}
+
+#[derive(Debug, Args)]
+pub struct LintData {
+    #[command(flatten)]
+    pub selection: SelectionData,
+    #[command(flatten)]
+    pub features: FeaturesData,
+}
+
+/// Which leaves of a workspace a command works on
+#[derive(Debug, Args, Clone)]
+pub struct SelectionData {
+    /// Only work on the named leaf of the workspace, can be given more than once
+    #[arg(short = 'p', long = "package", value_name = "NAME")]
+    pub packages: Vec<String>,
+    /// Work on every leaf in the workspace
+    #[arg(long, default_value_t = false, conflicts_with = "packages")]
+    pub workspace: bool,
+}
+
+/// Which features the selected leaves are built with, their dependencies get the features they're
+/// depended on with
+#[derive(Debug, Args, Clone, Default)]
+pub struct FeaturesData {
+    /// Features of the selected leaves to enable, separated by commas
+    #[arg(short = 'F', long, value_delimiter = ',')]
+    pub features: Vec<String>,
+    /// Enable every feature of the selected leaves
+    #[arg(long, default_value_t = false)]
+    pub all_features: bool,
+    /// Don't enable the `default` features of the selected leaves
+    #[arg(long, default_value_t = false)]
+    pub no_default_features: bool,
+}
+
+/// Whether tea.lock may be rewritten
+#[derive(Debug, Args, Clone)]
+pub struct LockData {
+    /// Fail if tea.lock is missing or out of date instead of updating it
+    #[arg(long, default_value_t = false)]
+    pub locked: bool,
+    /// Like --locked, and never fetch anything over the network
+    #[arg(long, default_value_t = false)]
+    pub frozen: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct TreeData {
+    /// Show the leaves depending on this leaf instead of its dependencies
+    #[arg(short, long, value_name = "LEAF", conflicts_with = "duplicates")]
+    pub invert: Option<String>,
+    /// Only show leaves that are built more than once, and what depends on each build
+    #[arg(short, long, default_value_t = false)]
+    pub duplicates: bool,
+    #[arg(long, value_enum, default_value_t = TreeFormat::Text)]
+    pub format: TreeFormat,
+    #[command(flatten)]
+    pub selection: SelectionData,
+    #[command(flatten)]
+    pub features: FeaturesData,
+    #[command(flatten)]
+    pub lock: LockData,
+}
+
+/// How `tree` prints the graph
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum TreeFormat {
+    /// An indented tree
+    Text,
+    /// A graphviz digraph
+    Dot,
+}
+
+#[derive(Debug, Args)]
+pub struct MetadataData {
+    #[arg(long, value_enum, default_value_t = MetadataFormat::Json)]
+    pub format: MetadataFormat,
+    #[command(flatten)]
+    pub build: BrewData,
+}
+
+/// How `metadata` prints the graph
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum MetadataFormat {
+    Json,
+}
+
+#[derive(Debug, Args)]
+pub struct CompdbData {
+    /// Where to write compile_commands.json, defaults to the project root
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    #[command(flatten)]
+    pub build: BrewData,
+}
+
+#[derive(Debug, Args)]
+pub struct MatrixData {
+    /// Build with the default features, no features, then each feature on its own. The default
+    /// without a [matrix] in tea.toml
+    #[arg(long, default_value_t = false, conflicts_with = "powerset")]
+    pub each_feature: bool,
+    /// Build every combination of features
+    #[arg(long, default_value_t = false)]
+    pub powerset: bool,
+    /// Only combine up to this many features with --powerset
+    #[arg(long, requires = "powerset")]
+    pub depth: Option<usize>,
+    /// Run the tests of each combination instead of only building it
+    #[arg(long, default_value_t = false)]
+    pub sip: bool,
+    #[command(flatten)]
+    pub build: BrewData,
+}
+
+#[derive(Debug, Args)]
+pub struct PackageData {
+    #[command(flatten)]
+    pub build: BrewData,
+    /// Don't check that the packaged leaf builds on its own
+    #[arg(long, default_value_t = false)]
+    pub no_verify: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct PublishData {
+    #[command(flatten)]
+    pub package: PackageData,
+    /// The registry to publish to, defaults to the one named default
+    #[arg(long)]
+    pub registry: Option<String>,
 }
 
 #[derive(Debug, Args)]
 pub struct AddData {
+    /// The name of the dependency, defaults to the package.name of the leaf at --path or --git
+    pub name: Option<String>,
+    /// Depend on the leaf in this directory
+    #[arg(long, conflicts_with = "git")]
+    pub path: Option<PathBuf>,
+    /// Depend on the leaf in this git repository
     #[arg(long)]
-    pub features: Option<String>,
+    pub git: Option<String>,
+    /// The tag of the git repository to check out
+    #[arg(long, requires = "git", conflicts_with_all = ["branch", "rev"])]
+    pub tag: Option<String>,
+    /// The branch of the git repository to check out
+    #[arg(long, requires = "git", conflicts_with = "rev")]
+    pub branch: Option<String>,
+    /// The commit of the git repository to check out
+    #[arg(long, requires = "git")]
+    pub rev: Option<String>,
+    /// The versions of the leaf that may be used, like ^1.2
     #[arg(long)]
-    pub path: PathBuf,
+    pub version: Option<String>,
+    /// The registry to take the leaf from, defaults to the one named default
+    #[arg(long, requires = "version", conflicts_with = "git")]
+    pub registry: Option<String>,
+    /// Features to enable on the dependency, separated by commas
+    #[arg(long, value_delimiter = ',')]
+    pub features: Vec<String>,
+    /// Add it to [dev-dependencies], which are only used by sip
+    #[arg(long, default_value_t = false)]
+    pub dev: bool,
+    /// Only depend on it when this feature of the leaf is enabled
+    #[arg(long, value_name = "FEATURE")]
+    pub feature_gated: Option<String>,
+}
 
+#[derive(Debug, Args)]
+pub struct RemoveData {
+    /// The name of the dependency to remove
     pub name: String,
+    /// Remove it from [dev-dependencies]
+    #[arg(long, default_value_t = false)]
+    pub dev: bool,
+    /// Remove it from the dependencies of this feature
+    #[arg(long, value_name = "FEATURE")]
+    pub feature_gated: Option<String>,
+}
+
+impl BrewData {
+    pub fn profile_name(&self) -> &str {
+        if self.release {
+            RELEASE_PROFILE
+        } else if self.debug {
+            DEV_PROFILE
+        } else {
+            self.profile.as_deref().unwrap_or(DEV_PROFILE)
+        }
+    }
+}
+
+impl LockData {
+    pub fn mode(&self) -> LockMode {
+        if self.frozen {
+            LockMode::Frozen
+        } else if self.locked {
+            LockMode::Locked
+        } else {
+            LockMode::Update
+        }
+    }
 }
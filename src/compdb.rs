@@ -0,0 +1,25 @@
+use std::path::Path;
+
+use crate::{
+    compiler::CompileCommand,
+    error::{Error, Result},
+    graph::LeafGraph,
+    write_if_changed, Build,
+};
+
+pub const COMPILE_COMMANDS: &str = "compile_commands.json";
+
+/// Writes the command compiling each source of every leaf in the graph to `path`, as
+/// compile_commands.json. Every source is listed whether or not it's stale, with the same include
+/// directories and defines the build compiles it with
+pub fn write(leaves: &LeafGraph, build: &Build, path: &Path) -> Result<()> {
+    let mut commands: Vec<CompileCommand> = Vec::new();
+    for leaf in leaves.leaves() {
+        let compiler = leaf.compiler(leaves, build)?;
+        commands.append(&mut compiler.compile_commands(&leaf.path, &leaf.sources()));
+    }
+
+    let contents = serde_json::to_string_pretty(&commands)
+        .map_err(|error| Error::Usage(format!("couldn't write {}: {}", path.display(), error)))?;
+    write_if_changed(path, &contents)
+}
@@ -1,18 +1,45 @@
 use std::{
-    path::{Path, PathBuf},
-    process::Command,
-    sync::atomic::{AtomicUsize, Ordering},
+    path::{Component, Path, PathBuf},
+    process::{Command, Output},
+    time::SystemTime,
 };
 
-use colored::Colorize;
-use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::Profile,
+    error::{Error, IoContext, Result},
+    toolchain::{
+        define_flag, include_flag, library_flag, optimization_flag, Toolchain, DEBUG_FLAG,
+        STANDARD_FLAG,
+    },
+};
 
 pub enum OutputType {
     Binary,
     Library,
 }
 
+/// A source whose object is missing or out of date
+pub struct StaleObject {
+    pub source: PathBuf,
+    object: PathBuf,
+    fingerprint: String,
+}
+
+/// An entry of compile_commands.json, the format clangd and other tools read compiler
+/// invocations from. Relative paths are relative to `directory`
+#[derive(Debug, Serialize)]
+pub struct CompileCommand {
+    pub directory: PathBuf,
+    pub file: PathBuf,
+    pub arguments: Vec<String>,
+    pub output: PathBuf,
+}
+
 pub struct Compiler {
+    toolchain: Toolchain,
     target_directory: PathBuf,
     compile_flags: Vec<String>,
     link_flags: Vec<String>,
@@ -22,23 +49,23 @@ pub struct Compiler {
 }
 
 impl Compiler {
-    pub fn new(target_directory: &Path) -> Self {
+    pub fn new(target_directory: &Path, toolchain: &Toolchain) -> Self {
         Self {
+            toolchain: toolchain.clone(),
             target_directory: target_directory.to_owned(),
-            compile_flags: vec!["-std=c11".to_owned()],
-            link_flags: vec!["-lm".to_owned(), "-std=c11".to_owned()],
+            compile_flags: vec![STANDARD_FLAG.to_owned()],
+            link_flags: vec![library_flag("m"), STANDARD_FLAG.to_owned()],
             objects: Vec::new(),
             defines: Vec::new(),
         }
     }
 
     pub fn include(&mut self, path: &Path) {
-        self.compile_flags.push(format!("-I{}", path.display()));
+        self.compile_flags.push(include_flag(path));
     }
 
-    pub fn add_static_library(&mut self, name: &str) {
-        self.objects
-            .push(self.target_directory.join(format!("lib{}.a", name)));
+    pub fn add_static_library(&mut self, path: &Path) {
+        self.objects.push(path.to_owned());
     }
 
     pub fn define<T: ToString>(&mut self, name: &str, value: Option<T>) {
@@ -46,110 +73,459 @@ impl Compiler {
             .push((name.to_owned(), value.map(|s| s.to_string())));
     }
 
-    pub fn set_optimization_level(&mut self, level: u32) {
-        self.compile_flags.push(format!("-O{}", level));
-        self.link_flags.push(format!("-O{}", level));
+    pub fn set_optimization_level(&mut self, level: &str) {
+        let flag = optimization_flag(level);
+        self.compile_flags.push(flag.clone());
+        self.link_flags.push(flag);
     }
 
     pub fn enable_debug_info(&mut self) {
-        self.compile_flags.push("-g".to_owned());
-        self.link_flags.push("-g".to_owned());
+        self.compile_flags.push(DEBUG_FLAG.to_owned());
+        self.link_flags.push(DEBUG_FLAG.to_owned());
+    }
+
+    pub fn add_compile_flag(&mut self, flag: &str) {
+        self.compile_flags.push(flag.to_owned());
+    }
+
+    pub fn add_link_flag(&mut self, flag: &str) {
+        self.link_flags.push(flag.to_owned());
     }
-    
+
+    /// Applies a profile's optimisation, debug info, sanitizers, flags and defines
+    pub fn use_profile(&mut self, profile: &Profile) -> Result<()> {
+        self.set_optimization_level(&profile.opt_level);
+        if profile.debug {
+            self.enable_debug_info();
+        }
+
+        let sanitizers = self
+            .toolchain
+            .sanitizer_flags(&profile.sanitizers)
+            .map_err(|sanitizer| {
+                Error::toolchain(
+                    &self.toolchain.compiler,
+                    format!("the {} sanitizer isn't supported", sanitizer),
+                )
+            })?;
+        sanitizers.iter().for_each(|flag| {
+            self.add_compile_flag(flag);
+            self.add_link_flag(flag);
+        });
+
+        profile
+            .cflags
+            .iter()
+            .for_each(|flag| self.add_compile_flag(flag));
+        profile
+            .ldflags
+            .iter()
+            .for_each(|flag| self.add_link_flag(flag));
+        profile
+            .defines
+            .iter()
+            .for_each(|(name, value)| self.define(name, value.clone()));
+
+        Ok(())
+    }
+
     pub fn add_system_library(&mut self, name: &str) {
-        self.link_flags.push(format!("-l{}", name));
-    }
-
-    pub fn compile(&mut self, paths: &[PathBuf], name: &str) {
-        let progress = AtomicUsize::new(0);
-
-        paths.par_iter().for_each(|path| {
-            let obj = self
-                .target_directory
-                .clone()
-                .join("objects")
-                .join(path.with_extension("o"));
-            std::fs::create_dir_all(obj.parent().unwrap()).unwrap();
-            let mut cmd = Command::new("tcc");
-
-            self.defines.iter().for_each(|(name, value)| {
-                if let Some(v) = value {
-                    cmd.arg(&format!("-D{}={}", name, v));
-                } else {
-                    cmd.arg(&format!("-D{}", name));
-                }
-            });
+        self.link_flags.push(library_flag(name));
+    }
 
-            cmd.args(&self.compile_flags)
-                .arg("-c")
-                .arg(path)
-                .arg("-o")
-                .arg(obj.clone());
-
-            let output = cmd
-                .output()
-                .expect(&format!("Failed to compile {}", path.display()));
-
-            if !output.status.success() {
-                println!("{:#?}", cmd);
-                println!("{}", String::from_utf8(output.stdout).unwrap());
-                println!("{}", String::from_utf8(output.stderr).unwrap());
-                panic!("{} failed to compile", path.display());
-            }
+    /// Objects mirror the source tree below `root`, so a dependency at `../lib` doesn't escape
+    /// the target directory
+    fn object_path(&self, root: &Path, path: &Path) -> PathBuf {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let relative: PathBuf = relative
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
 
-            let value = progress.fetch_add(1, Ordering::SeqCst);
-            let red = 1.0 - (value as f32 / paths.len() as f32);
-            let green = value as f32 / paths.len() as f32;
-            let progress_str = format!("[{}/{}]", value, paths.len())
-                .truecolor((red * 256.0) as u8, (green * 256.0) as u8, 0)
-                .bold();
-            print!("\r                                 ");
-            print!(
-                "\r{:13} {} {}",
-                progress_str,
-                "Compiling".green().bold(),
-                name
-            );
+        self.target_directory
+            .join("objects")
+            .join(relative.with_extension("o"))
+    }
+
+    fn artifact_path(&self, name: &str, output: &OutputType) -> PathBuf {
+        let file: String = match output {
+            OutputType::Binary => name.to_owned(),
+            OutputType::Library => format!("lib{}.a", name),
+        };
+
+        self.target_directory.join(file)
+    }
+
+    /// Hashes everything that affects the contents of an object, any change forces a rebuild
+    fn object_fingerprint(&self, source: &Path) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(std::fs::read(source).unwrap_or_default());
+        hash_strings(&mut hasher, &self.compile_flags);
+        self.defines.iter().for_each(|(name, value)| {
+            hash_strings(&mut hasher, &[name, value.as_deref().unwrap_or_default()]);
+        });
+        hash_strings(
+            &mut hasher,
+            &[&self.toolchain.compiler, &self.toolchain.version],
+        );
+
+        to_hex(&hasher.finalize())
+    }
+
+    /// Hashes the inputs and flags of a link, so removing a source or library relinks
+    fn artifact_fingerprint(&self, output: &OutputType) -> String {
+        let mut hasher = Sha256::new();
+        match output {
+            OutputType::Binary => hash_strings(&mut hasher, &["binary"]),
+            OutputType::Library => hash_strings(&mut hasher, &["library"]),
+        }
+        hash_strings(&mut hasher, &self.link_flags);
+        self.objects.iter().for_each(|object| {
+            hash_strings(&mut hasher, &[object.display().to_string()]);
         });
+        hash_strings(
+            &mut hasher,
+            &[&self.toolchain.compiler, &self.toolchain.version],
+        );
+
+        to_hex(&hasher.finalize())
+    }
+
+    /// Adds every source below `root` to the objects being linked, returning the ones that need
+    /// to be rebuilt
+    pub fn add_sources(&mut self, root: &Path, paths: &[PathBuf]) -> Vec<StaleObject> {
+        let stale = paths
+            .iter()
+            .map(|path| StaleObject {
+                source: path.clone(),
+                object: self.object_path(root, path),
+                fingerprint: self.object_fingerprint(path),
+            })
+            .filter(|stale| !is_object_fresh(&stale.object, &stale.fingerprint))
+            .collect();
 
         paths.iter().for_each(|path| {
-            let obj = self
-                .target_directory
-                .clone()
-                .join("objects")
-                .join(path.with_extension("o"));
+            let obj = self.object_path(root, path);
             self.objects.insert(0, obj);
         });
+
+        stale
     }
 
-    pub fn link(&self, name: &str, output: OutputType) {
-        let file: String = match output {
-            OutputType::Binary => name.to_owned(),
-            OutputType::Library => format!("lib{}.a", name),
+    fn compile_command(&self, source: &Path, object: &Path) -> Command {
+        let mut cmd = self.toolchain.command();
+
+        self.defines.iter().for_each(|(name, value)| {
+            cmd.arg(define_flag(name, value.as_deref()));
+        });
+
+        cmd.args(&self.compile_flags)
+            .arg("-c")
+            .arg(source)
+            .arg("-o")
+            .arg(object)
+            .args(self.toolchain.depfile_flags(&object.with_extension("d")));
+        cmd
+    }
+
+    /// The command compiling each of `paths`, whether or not it's stale, for
+    /// compile_commands.json
+    pub fn compile_commands(&self, root: &Path, paths: &[PathBuf]) -> Vec<CompileCommand> {
+        let directory = std::env::current_dir().unwrap_or_default();
+        paths
+            .iter()
+            .map(|path| {
+                let object = self.object_path(root, path);
+                let cmd = self.compile_command(path, &object);
+                CompileCommand {
+                    directory: directory.clone(),
+                    file: path.clone(),
+                    arguments: std::iter::once(cmd.get_program())
+                        .chain(cmd.get_args())
+                        .map(|arg| arg.to_string_lossy().into_owned())
+                        .collect(),
+                    output: object,
+                }
+            })
+            .collect()
+    }
+
+    /// Compiles a single object, returning the compiler's output on failure
+    pub fn compile_object(&self, stale: &StaleObject) -> Result<()> {
+        let obj = &stale.object;
+        if let Some(parent) = obj.parent() {
+            std::fs::create_dir_all(parent).with_path(parent)?;
+        }
+        // A stale fingerprint must never outlive a failed compile
+        let _ = std::fs::remove_file(obj.with_extension("fp"));
+        let mut cmd = self.compile_command(&stale.source, obj);
+
+        let output = cmd.output().map_err(|error| {
+            Error::toolchain(&self.toolchain.compiler, format!("couldn't run: {}", error))
+        })?;
+
+        if !output.status.success() {
+            return Err(Error::Compile {
+                source: stale.source.clone(),
+                command: command_line(&cmd),
+                output: combined_output(&output),
+            });
+        }
+
+        let fingerprint = obj.with_extension("fp");
+        std::fs::write(&fingerprint, &stale.fingerprint).with_path(&fingerprint)
+    }
+
+    /// Returns true if the artifact was linked with the same inputs and flags, and is newer than
+    /// every object or library it's made from
+    pub fn is_up_to_date(&self, name: &str, output: OutputType) -> bool {
+        let artifact_path = self.artifact_path(name, &output);
+        let Some(artifact) = modified(&artifact_path) else {
+            return false;
         };
+        if read_fingerprint(&artifact_path) != Some(self.artifact_fingerprint(&output)) {
+            return false;
+        }
+
+        self.objects
+            .iter()
+            .all(|object| modified(object).is_some_and(|time| time <= artifact))
+    }
 
-        let artifact_path = self.target_directory.join(file);
-
-        let output = match output {
-            OutputType::Binary => Command::new("tcc")
-                .args(&self.link_flags)
-                .args(&self.objects)
-                .arg("-o")
-                .arg(artifact_path)
-                .output()
-                .expect("Failed to link"),
-            OutputType::Library => Command::new("ar")
-                .arg("rcs")
-                .arg(artifact_path)
-                .args(&self.objects)
-                .output()
-                .expect("Failed to archive"),
+    /// Links or archives the artifact, returning the tool's output on failure
+    pub fn link(&self, name: &str, output: OutputType) -> Result<()> {
+        let artifact_path = self.artifact_path(name, &output);
+        let fingerprint = self.artifact_fingerprint(&output);
+
+        if let OutputType::Library = output {
+            // ar only replaces members, so start fresh to drop objects of deleted sources
+            let _ = std::fs::remove_file(&artifact_path);
+        }
+
+        let mut cmd = match output {
+            OutputType::Binary => {
+                let mut cmd = self.toolchain.command();
+                cmd.args(&self.link_flags)
+                    .args(&self.objects)
+                    .arg("-o")
+                    .arg(&artifact_path);
+                cmd
+            }
+            OutputType::Library => {
+                let mut cmd = self.toolchain.archive_command();
+                cmd.arg("rcs").arg(&artifact_path).args(&self.objects);
+                cmd
+            }
         };
 
+        let output = cmd.output().map_err(|error| {
+            Error::toolchain(
+                &cmd.get_program().to_string_lossy(),
+                format!("couldn't run: {}", error),
+            )
+        })?;
+
         if !output.status.success() {
-            println!("{}", String::from_utf8(output.stdout).unwrap());
-            println!("{}", String::from_utf8(output.stderr).unwrap());
-            panic!("{} failed to link", name);
+            return Err(Error::Link {
+                artifact: artifact_path.display().to_string(),
+                output: combined_output(&output),
+            });
+        }
+
+        let fingerprint_path = fingerprint_path(&artifact_path);
+        std::fs::write(&fingerprint_path, fingerprint).with_path(&fingerprint_path)
+    }
+}
+
+/// Renders a command the way it would be typed into a shell
+fn command_line(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn combined_output(output: &Output) -> String {
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    text
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).ok()?.modified().ok()
+}
+
+/// Parses a make style depfile as written by `-MD -MF`, returning every prerequisite
+fn parse_depfile(text: &str) -> Vec<PathBuf> {
+    let text = text.replace("\\\n", " ").replace("\\\r\n", " ");
+    let mut paths = Vec::new();
+    let mut current = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&' ') => {
+                current.push(' ');
+                chars.next();
+            }
+            c if c.is_whitespace() => {
+                if !current.is_empty() {
+                    paths.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
         }
     }
+    if !current.is_empty() {
+        paths.push(current);
+    }
+
+    // Drop the targets, everything after the first "target:" is a prerequisite
+    let start = paths
+        .iter()
+        .position(|path| path.ends_with(':'))
+        .map_or(0, |index| index + 1);
+
+    paths
+        .into_iter()
+        .skip(start)
+        .filter(|path| !path.ends_with(':'))
+        .map(PathBuf::from)
+        .collect()
+}
+
+fn hash_strings<T: AsRef<str>>(hasher: &mut Sha256, strings: &[T]) {
+    strings.iter().for_each(|string| {
+        hasher.update(string.as_ref().as_bytes());
+        hasher.update([0]);
+    });
+}
+
+/// Formats bytes as lowercase hex, as every hash teapot writes is
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn fingerprint_path(artifact: &Path) -> PathBuf {
+    let mut path = artifact.as_os_str().to_owned();
+    path.push(".fp");
+    PathBuf::from(path)
+}
+
+fn read_fingerprint(artifact: &Path) -> Option<String> {
+    std::fs::read_to_string(fingerprint_path(artifact)).ok()
+}
+
+/// An object is fresh if its fingerprint matches and it's newer than the source and every header
+/// listed in its depfile
+fn is_object_fresh(object: &Path, fingerprint: &str) -> bool {
+    let Some(object_time) = modified(object) else {
+        return false;
+    };
+    if std::fs::read_to_string(object.with_extension("fp"))
+        .ok()
+        .as_deref()
+        != Some(fingerprint)
+    {
+        return false;
+    }
+    let Ok(depfile) = std::fs::read_to_string(object.with_extension("d")) else {
+        return false;
+    };
+
+    parse_depfile(&depfile)
+        .into_iter()
+        .all(|dependency| modified(&dependency).is_some_and(|time| time <= object_time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::toolchain::ToolchainKind;
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn depfile_continuation_lines() {
+        let depfile = "obj/main.o: src/main.c \\\n include/core.h \\\n  include/util.h\n";
+        assert_eq!(
+            parse_depfile(depfile),
+            paths(&["src/main.c", "include/core.h", "include/util.h"])
+        );
+    }
+
+    #[test]
+    fn depfile_crlf_continuation_lines() {
+        let depfile = "obj/main.o: src/main.c \\\r\n include/core.h\r\n";
+        assert_eq!(
+            parse_depfile(depfile),
+            paths(&["src/main.c", "include/core.h"])
+        );
+    }
+
+    #[test]
+    fn depfile_escaped_spaces() {
+        let depfile = "obj/main.o: src/my\\ main.c include/a\\ b\\ c.h\n";
+        assert_eq!(
+            parse_depfile(depfile),
+            paths(&["src/my main.c", "include/a b c.h"])
+        );
+    }
+
+    #[test]
+    fn depfile_multiple_targets() {
+        let depfile = "obj/main.o obj/main.d: src/main.c include/core.h\n";
+        assert_eq!(
+            parse_depfile(depfile),
+            paths(&["src/main.c", "include/core.h"])
+        );
+    }
+
+    #[test]
+    fn depfile_phony_targets() {
+        let depfile = "obj/main.o: src/main.c include/core.h\n\ninclude/core.h:\n";
+        assert_eq!(
+            parse_depfile(depfile),
+            paths(&["src/main.c", "include/core.h"])
+        );
+    }
+
+    #[test]
+    fn fingerprints_change_with_flags_defines_and_the_compiler() {
+        let source = Path::new(file!());
+        let toolchain = Toolchain {
+            kind: ToolchainKind::Tcc,
+            compiler: "tcc".to_owned(),
+            archiver: None,
+            version: "0.9.27".to_owned(),
+            depfiles: true,
+        };
+        let fingerprint = |change: &dyn Fn(&mut Compiler)| {
+            let mut compiler = Compiler::new(Path::new("target"), &toolchain);
+            change(&mut compiler);
+            compiler.object_fingerprint(source)
+        };
+
+        let base = fingerprint(&|_| {});
+        assert_eq!(fingerprint(&|_| {}), base);
+        assert_ne!(
+            fingerprint(&|compiler| compiler.add_compile_flag("-Wall")),
+            base
+        );
+        assert_ne!(
+            fingerprint(&|compiler| compiler.define("FEATURE_FAST", None::<&str>)),
+            base
+        );
+        assert_ne!(
+            fingerprint(&|compiler| compiler.define("LEVEL", Some(2))),
+            fingerprint(&|compiler| compiler.define("LEVEL", Some(3)))
+        );
+        assert_ne!(
+            fingerprint(&|compiler| compiler.toolchain.version = "0.9.28".to_owned()),
+            base
+        );
+    }
 }
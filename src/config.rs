@@ -1,114 +1,720 @@
 use std::{
     collections::HashMap,
+    io::ErrorKind,
     path::{Path, PathBuf},
 };
 
-use toml_edit::{Document, Item, Table, Value};
+use semver::{Version, VersionReq};
+use toml_edit::{Document, Item, Table, TableLike, Value};
+
+use crate::{
+    diagnostic::Severity,
+    error::{self, Error},
+    git::{GitReference, GitSource},
+    graph::{canonical_path, normalize_path},
+    layout::{PACKAGE_DIRECTORY, PROFILE_SUBDIRECTORIES},
+    manifest,
+    registry::{RegistrySource, DEFAULT_REGISTRY},
+    workspace::Workspace,
+};
 
 #[derive(Debug)]
 pub struct TeaConfig {
     pub package: Package,
     pub dependencies: Dependencies,
+    /// Only used when the leaf's tests are built by `sip`
+    pub dev_dependencies: Dependencies,
+    pub features: Features,
+    pub constraints: Constraints,
+    /// The combinations of features `tpot matrix` builds, from `[matrix]`
+    pub matrix: Option<Vec<Vec<String>>>,
     pub defines: Defines,
-    pub libraries: Libraries
+    pub libraries: Libraries,
+    pub build: BuildConfig,
 }
 
 pub const BASE_FEATURES: &[&str] = &["windows", "linux"];
+/// Asks for a leaf's `default` features along with the ones named, unless a dependency sets
+/// `default-features = false`
+pub const DEFAULT_FEATURE: &str = "default";
 
 impl TeaConfig {
-    pub fn parse(path: &Path) -> Option<Self> {
-        let text = String::from_utf8(std::fs::read(path.join("tea.toml")).unwrap_or_else(|_| {
-            println!("Can't find tea.toml in {}", path.display());
-            std::process::exit(1);
-        }))
-        .unwrap();
-        let document = text.parse::<Document>().ok()?;
-        let package = Package::parse(document.get("package")?.as_table()?)?;
+    /// Parses a leaf's tea.toml, `workspace` holds what it can inherit if it's in a workspace
+    pub fn parse(document: &Document, workspace: Option<&Inheritable>) -> Result<Self, String> {
+        let mut package = Package::parse(
+            table(document, "package")?.ok_or("missing [package]")?,
+            workspace,
+        )?;
+        let features = table(document, "features")?
+            .map(Features::parse)
+            .transpose()?
+            .unwrap_or_default();
+        // Features in [features] don't need to be listed in package.features as well
+        for (name, _) in &features.enables {
+            if !package.features.contains(name) {
+                package.features.push(name.clone());
+            }
+        }
 
         let mut all_features = BASE_FEATURES
             .iter()
             .map(ToString::to_string)
             .collect::<Vec<String>>();
         all_features.append(&mut package.features.clone());
-        let dependencies =
-            Dependencies::parse(document.get("dependencies")?.as_table()?, &all_features);
-        let defines = document
-            .get("defines")
-            .map(|item| Defines::parse(item.as_table().unwrap(), &all_features))
-            .unwrap_or_else(Defines::default);
-        let libraries = document.get("libraries")
-            .map(|item| Libraries::parse(item.as_table().unwrap(), &all_features))
-            .unwrap_or_else(Libraries::default);
+        let dependencies = table(document, "dependencies")?
+            .map(|table| Dependencies::parse(table, &all_features, workspace))
+            .transpose()?
+            .unwrap_or_default();
+        let dev_dependencies = table(document, "dev-dependencies")?
+            .map(|table| Dependencies::parse(table, &all_features, workspace))
+            .transpose()?
+            .unwrap_or_default();
+        let defines = table(document, "defines")?
+            .map(|table| Defines::parse(table, &all_features, workspace))
+            .transpose()?
+            .unwrap_or_default();
+        let libraries = table(document, "libraries")?
+            .map(|table| Libraries::parse(table, &all_features))
+            .transpose()?
+            .unwrap_or_default();
 
-        Some(Self {
+        features.check(&all_features, &dependencies, &dev_dependencies)?;
+        let constraints = table(document, "constraints")?
+            .map(Constraints::parse)
+            .transpose()?
+            .unwrap_or_default();
+        constraints.check(&all_features)?;
+        let matrix = table(document, "matrix")?
+            .map(|table| parse_matrix(table, &all_features))
+            .transpose()?;
+
+        Ok(Self {
             package,
             dependencies,
+            dev_dependencies,
+            features,
+            constraints,
+            matrix,
             defines,
-            libraries
+            libraries,
+            build: BuildConfig::parse(document)?,
+        })
+    }
+}
+
+/// The parts of tea.toml that configure the whole build rather than a single leaf, in a
+/// workspace these are taken from the workspace root
+#[derive(Debug, Default, Clone)]
+pub struct BuildConfig {
+    pub toolchain: ToolchainConfig,
+    pub profiles: HashMap<String, ProfileConfig>,
+    pub registries: HashMap<String, PathBuf>,
+}
+
+impl BuildConfig {
+    pub fn parse(document: &Document) -> Result<Self, String> {
+        let toolchain = table(document, "toolchain")?
+            .map(ToolchainConfig::parse)
+            .unwrap_or_default();
+        let profiles = table(document, "profile")?
+            .map(|table| {
+                table
+                    .iter()
+                    .map(|(name, item)| {
+                        if name == PACKAGE_DIRECTORY {
+                            return Err(format!(
+                                "[profile.{}] can't be used, `tpot package` writes to target/{}",
+                                name, PACKAGE_DIRECTORY
+                            ));
+                        }
+                        let profile = item
+                            .as_table()
+                            .ok_or_else(|| format!("[profile.{}] must be a table", name))?;
+                        Ok((name.to_owned(), ProfileConfig::parse(profile)?))
+                    })
+                    .collect::<Result<HashMap<_, _>, String>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Self {
+            toolchain,
+            profiles,
+            registries: parse_registries(document)?,
+        })
+    }
+}
+
+/// Parses `[registries]`, which maps each registry's name to its directory, like
+/// `internal = { path = "/mnt/share/teapot" }`
+pub fn parse_registries(document: &Document) -> Result<HashMap<String, PathBuf>, String> {
+    let Some(registries) = table(document, "registries")? else {
+        return Ok(HashMap::new());
+    };
+
+    registries
+        .iter()
+        .map(|(name, item)| {
+            let path = item
+                .as_table_like()
+                .and_then(|registry| registry.get("path"))
+                .and_then(|path| path.as_str())
+                .ok_or_else(|| {
+                    format!(
+                        "registry {} must be a table with a path, like {} = {{ path = \"...\" }}",
+                        name, name
+                    )
+                })?;
+            Ok((name.to_owned(), PathBuf::from(path)))
         })
+        .collect()
+}
+
+/// The `[workspace]` table, listing the leaves built together with this one
+#[derive(Debug, Default)]
+pub struct WorkspaceConfig {
+    pub members: Vec<String>,
+    pub exclude: Vec<String>,
+    pub inheritable: Inheritable,
+}
+
+impl WorkspaceConfig {
+    /// Returns None if the manifest isn't a workspace root. Dependency paths are relative to
+    /// `root`, the directory of the workspace root
+    pub fn parse(document: &Document, root: &Path) -> Result<Option<Self>, String> {
+        let Some(table) = table(document, "workspace")? else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            members: table
+                .get("members")
+                .and_then(parse_string_array)
+                .unwrap_or_default(),
+            exclude: table
+                .get("exclude")
+                .and_then(parse_string_array)
+                .unwrap_or_default(),
+            inheritable: Inheritable::parse(table, root)?,
+        }))
     }
 }
 
+/// Values members of a workspace can inherit by setting `workspace = true` instead of a value
+#[derive(Debug, Default, Clone)]
+pub struct Inheritable {
+    pub version: Option<String>,
+    pub features: Option<Vec<String>>,
+    pub dependencies: HashMap<String, Dependency>,
+    pub defines: HashMap<String, Option<String>>,
+}
+
+impl Inheritable {
+    fn parse(workspace: &Table, root: &Path) -> Result<Self, String> {
+        let sub_table = |key: &str| {
+            workspace
+                .get(key)
+                .map(|item| {
+                    item.as_table()
+                        .ok_or_else(|| format!("workspace.{} must be a table", key))
+                })
+                .transpose()
+        };
+
+        let package = sub_table("package")?;
+        let dependencies = sub_table("dependencies")?
+            .map(|table| {
+                table
+                    .iter()
+                    .map(|(name, item)| {
+                        let mut dependency = Dependency::parse(name, item, None)?;
+                        dependency.path =
+                            dependency.path.map(|path| normalize_path(&root.join(path)));
+                        Ok((name.to_owned(), dependency))
+                    })
+                    .collect::<Result<HashMap<_, _>, String>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+        let defines = sub_table("defines")?
+            .map(|table| {
+                table
+                    .iter()
+                    .map(|(name, item)| Defines::parse_define(name, item, None))
+                    .collect::<Result<HashMap<_, _>, String>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Self {
+            version: package
+                .and_then(|package| package.get("version"))
+                .and_then(|item| item.as_str())
+                .map(ToOwned::to_owned),
+            features: package
+                .and_then(|package| package.get("features"))
+                .and_then(parse_string_array),
+            dependencies,
+            defines,
+        })
+    }
+}
+
+/// Whether a value is `{ workspace = true }`, meaning it's inherited from the workspace root
+pub fn is_inherited(item: &Item) -> bool {
+    item.as_table_like()
+        .and_then(|table| table.get("workspace"))
+        .and_then(|item| item.as_bool())
+        .unwrap_or(false)
+}
+
+/// Looks up an inherited value, `key` names it in errors
+fn inherit<'a, T>(
+    workspace: Option<&'a Inheritable>,
+    key: &str,
+    get: impl FnOnce(&'a Inheritable) -> Option<&'a T>,
+) -> Result<&'a T, String> {
+    let workspace = workspace.ok_or_else(|| {
+        format!(
+            "{} is inherited from the workspace, but this leaf isn't in one",
+            key
+        )
+    })?;
+    get(workspace).ok_or_else(|| format!("{} is inherited, but the workspace doesn't set it", key))
+}
+
+/// Every tea.toml read so far, so each is only read and validated once
+#[derive(Default)]
+pub struct Manifests {
+    documents: HashMap<PathBuf, Document>,
+}
+
+impl Manifests {
+    /// Reads the tea.toml in `path`, printing its warnings and failing on its errors
+    pub fn document(&mut self, path: &Path) -> error::Result<&Document> {
+        let manifest = path.join("tea.toml");
+        let key = canonical_path(path).join("tea.toml");
+        if !self.documents.contains_key(&key) {
+            let document = Self::read(&manifest)?;
+            self.documents.insert(key.clone(), document);
+        }
+
+        Ok(&self.documents[&key])
+    }
+
+    /// Reads the tea.toml of the leaf in `path`, resolving anything it inherits from its
+    /// workspace
+    pub fn config(&mut self, path: &Path) -> error::Result<TeaConfig> {
+        let inheritable = Workspace::find(self, path)?.map(|workspace| workspace.inheritable);
+        let document = self.document(path)?;
+        TeaConfig::parse(document, inheritable.as_ref())
+            .map_err(|message| Error::manifest(&path.join("tea.toml"), message))
+    }
+
+    fn read(manifest: &Path) -> error::Result<Document> {
+        let text = std::fs::read_to_string(manifest).map_err(|error| match error.kind() {
+            ErrorKind::NotFound => {
+                Error::manifest(manifest, "can't find tea.toml, is this a teapot leaf?")
+            }
+            _ => Error::manifest(manifest, error),
+        })?;
+
+        let (errors, warnings): (Vec<_>, Vec<_>) = manifest::validate(manifest, &text)
+            .into_iter()
+            .partition(|diagnostic| diagnostic.severity == Severity::Error);
+        warnings.iter().for_each(|warning| warning.emit());
+        if !errors.is_empty() {
+            return Err(Error::Invalid(errors));
+        }
+
+        text.parse::<Document>()
+            .map_err(|error| Error::manifest(manifest, error))
+    }
+}
+
+/// Gets an optional top level table, erroring if the key exists but isn't a table
+fn table<'a>(document: &'a Document, key: &str) -> Result<Option<&'a Table>, String> {
+    document
+        .get(key)
+        .map(|item| {
+            item.as_table()
+                .ok_or_else(|| format!("{} must be a table", key))
+        })
+        .transpose()
+}
+
 #[derive(Debug)]
 pub struct Package {
     pub name: String,
-    pub version: String,
+    pub version: Version,
     pub features: Vec<String>,
 }
 
 impl Package {
-    pub fn parse(table: &Table) -> Option<Self> {
-        Some(Self {
-            name: table.get("name")?.as_str()?.to_owned(),
-            version: table.get("version")?.as_str()?.to_owned(),
-            features: table
-                .get("features")
-                .map(|item| item.as_array())
-                .flatten()
-                .map(|array| {
-                    array
+    pub fn parse(table: &Table, workspace: Option<&Inheritable>) -> Result<Self, String> {
+        let string = |key: &str| {
+            table
+                .get(key)
+                .ok_or_else(|| format!("missing package.{}", key))?
+                .as_str()
+                .map(ToOwned::to_owned)
+                .ok_or_else(|| format!("package.{} must be a string", key))
+        };
+
+        let version = match table.get("version") {
+            Some(item) if is_inherited(item) => {
+                inherit(workspace, "package.version", |workspace| {
+                    workspace.version.as_ref()
+                })?
+                .clone()
+            }
+            _ => string("version")?,
+        };
+        let version = Version::parse(&version).map_err(|error| {
+            format!(
+                "package.version {} isn't a semver version like 1.2.3: {}",
+                version, error
+            )
+        })?;
+        let features = match table.get("features") {
+            Some(item) if is_inherited(item) => {
+                inherit(workspace, "package.features", |workspace| {
+                    workspace.features.as_ref()
+                })?
+                .clone()
+            }
+            item => item.and_then(parse_string_array).unwrap_or_default(),
+        };
+
+        let name = string("name")?;
+        if PROFILE_SUBDIRECTORIES.contains(&name.as_str()) {
+            return Err(format!(
+                "package.name can't be {}, binaries are built next to the {} directories",
+                name,
+                PROFILE_SUBDIRECTORIES.join(" and ")
+            ));
+        }
+
+        Ok(Self {
+            name,
+            version,
+            features,
+        })
+    }
+}
+
+/// What enabling a feature also turns on, one entry of a feature's array in `[features]`
+#[derive(Debug, Clone, PartialEq)]
+pub enum FeatureValue {
+    /// Another feature of the same leaf, like `"gl"`
+    Feature(String),
+    /// A feature of a dependency, like `"render/x11"`, which also activates the dependency if
+    /// it's optional
+    Dependency { name: String, feature: String },
+    /// An optional dependency, like `"dep:png"`
+    Optional(String),
+}
+
+impl FeatureValue {
+    fn parse(value: &str) -> Self {
+        if let Some(name) = value.strip_prefix("dep:") {
+            return Self::Optional(name.to_owned());
+        }
+        match value.split_once('/') {
+            Some((name, feature)) => Self::Dependency {
+                name: name.to_owned(),
+                feature: feature.to_owned(),
+            },
+            None => Self::Feature(value.to_owned()),
+        }
+    }
+}
+
+/// The `[features]` table, listing what each feature enables along with the `default` features
+#[derive(Debug, Default)]
+pub struct Features {
+    pub default: Vec<String>,
+    /// In the order they're declared, so defines are always in the same order
+    pub enables: Vec<(String, Vec<FeatureValue>)>,
+}
+
+impl Features {
+    pub fn parse(table: &Table) -> Result<Self, String> {
+        let mut features = Self::default();
+        for (name, item) in table.iter() {
+            let values = parse_string_array(item).ok_or_else(|| {
+                format!(
+                    "features.{} must be an array of the features it enables, like {} = []",
+                    name, name
+                )
+            })?;
+            if name == DEFAULT_FEATURE {
+                features.default = values;
+            } else {
+                features.enables.push((
+                    name.to_owned(),
+                    values
                         .iter()
-                        .filter_map(|v| v.as_str())
-                        .map(|str| str.to_owned())
-                        .collect::<Vec<String>>()
+                        .map(|value| FeatureValue::parse(value))
+                        .collect(),
+                ));
+            }
+        }
+
+        Ok(features)
+    }
+
+    /// What enabling `feature` also turns on
+    pub fn enables(&self, feature: &str) -> &[FeatureValue] {
+        self.enables
+            .iter()
+            .find(|(name, _)| name == feature)
+            .map(|(_, values)| values.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Fails if a feature enables something the leaf doesn't have. `all_features` includes the
+    /// platform features
+    fn check(
+        &self,
+        all_features: &[String],
+        dependencies: &Dependencies,
+        dev_dependencies: &Dependencies,
+    ) -> Result<(), String> {
+        let dependency = |name: &str| {
+            [dependencies, dev_dependencies]
+                .into_iter()
+                .flat_map(|dependencies| {
+                    dependencies
+                        .base
+                        .iter()
+                        .chain(dependencies.features.values().flatten())
                 })
-                .unwrap_or(Vec::new()),
+                .find(|dependency| dependency.name == name)
+        };
+
+        if let Some(feature) = self
+            .default
+            .iter()
+            .find(|feature| !all_features.contains(feature))
+        {
+            return Err(format!(
+                "features.default enables {}, which isn't a feature",
+                feature
+            ));
+        }
+        for (name, values) in &self.enables {
+            for value in values {
+                match value {
+                    FeatureValue::Feature(feature) if !all_features.contains(feature) => {
+                        let mut message = format!(
+                            "feature {} enables {}, which isn't a feature",
+                            name, feature
+                        );
+                        if dependency(feature).is_some_and(|d| d.optional) {
+                            message.push_str(&format!(
+                                ", write dep:{} to activate the optional dependency",
+                                feature
+                            ));
+                        }
+                        return Err(message);
+                    }
+                    FeatureValue::Dependency { name: dep, .. } if dependency(dep).is_none() => {
+                        return Err(format!(
+                            "feature {} enables a feature of {}, which isn't a dependency",
+                            name, dep
+                        ))
+                    }
+                    FeatureValue::Optional(dep) if !dependency(dep).is_some_and(|d| d.optional) => {
+                        return Err(format!(
+                            "feature {} enables dep:{}, which isn't an optional dependency",
+                            name, dep
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses `[matrix]`, where `combinations` lists the sets of features to build. A set naming
+/// `default` includes the default features
+fn parse_matrix(table: &Table, all_features: &[String]) -> Result<Vec<Vec<String>>, String> {
+    let combinations = table
+        .get("combinations")
+        .and_then(Item::as_array)
+        .and_then(|combinations| {
+            combinations
+                .iter()
+                .map(|combination| parse_string_array(&Item::Value(combination.clone())))
+                .collect::<Option<Vec<Vec<String>>>>()
         })
+        .ok_or(
+            "matrix.combinations must be an array of sets of features, like [[\"x11\"], \
+             [\"wayland\", \"default\"]]",
+        )?;
+
+    match combinations
+        .iter()
+        .flatten()
+        .find(|feature| *feature != DEFAULT_FEATURE && !all_features.contains(feature))
+    {
+        Some(feature) => Err(format!(
+            "matrix.combinations names {}, which isn't a feature",
+            feature
+        )),
+        None => Ok(combinations),
     }
 }
 
-#[derive(Debug)]
+/// The `[constraints]` table, limiting which features can be enabled together
+#[derive(Debug, Default)]
+pub struct Constraints {
+    /// At most one feature of each group can be enabled
+    pub conflicts: Vec<Vec<String>>,
+    /// Exactly one feature of each group has to be enabled
+    pub one_of: Vec<Vec<String>>,
+    /// The features each feature needs. Each requirement lists alternatives, written `"a|b"`,
+    /// any of which meets it
+    pub requires: Vec<(String, Vec<Vec<String>>)>,
+}
+
+impl Constraints {
+    pub fn parse(table: &Table) -> Result<Self, String> {
+        let conflicts = parse_groups(table, "conflicts")?;
+        let one_of = parse_groups(table, "one-of")?;
+
+        let requires = match table.get("requires") {
+            Some(item) => {
+                let requires = item.as_table_like().ok_or(
+                    "constraints.requires must be a table of the features each feature needs, \
+                     like { vulkan = [\"wayland\"] }",
+                )?;
+                requires
+                    .iter()
+                    .map(|(feature, item)| {
+                        let requirements = parse_string_array(item).ok_or_else(|| {
+                            format!(
+                                "constraints.requires.{} must be an array of features",
+                                feature
+                            )
+                        })?;
+                        let requirements = requirements
+                            .iter()
+                            .map(|requirement| {
+                                requirement
+                                    .split('|')
+                                    .map(|alternative| alternative.trim().to_owned())
+                                    .collect()
+                            })
+                            .collect();
+                        Ok((feature.to_owned(), requirements))
+                    })
+                    .collect::<Result<Vec<_>, String>>()?
+            }
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            conflicts,
+            one_of,
+            requires,
+        })
+    }
+
+    /// Fails if a constraint names something that isn't a feature
+    fn check(&self, all_features: &[String]) -> Result<(), String> {
+        let requires = self.requires.iter().flat_map(|(feature, requirements)| {
+            std::iter::once(feature).chain(requirements.iter().flatten())
+        });
+        match self
+            .conflicts
+            .iter()
+            .chain(&self.one_of)
+            .flatten()
+            .chain(requires)
+            .find(|feature| !all_features.contains(feature))
+        {
+            Some(feature) => Err(format!(
+                "[constraints] names {}, which isn't a feature",
+                feature
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Groups of features under `key` of `[constraints]`, like `[["x11", "wayland"]]`
+fn parse_groups(table: &Table, key: &str) -> Result<Vec<Vec<String>>, String> {
+    match table.get(key) {
+        Some(item) => item
+            .as_array()
+            .and_then(|groups| {
+                groups
+                    .iter()
+                    .map(|group| parse_string_array(&Item::Value(group.clone())))
+                    .collect::<Option<Vec<Vec<String>>>>()
+            })
+            .ok_or_else(|| {
+                format!(
+                    "constraints.{} must be an array of groups of features, like \
+                     [[\"x11\", \"wayland\"]]",
+                    key
+                )
+            }),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct Dependencies {
     pub base: Vec<Dependency>,
     pub features: HashMap<String, Vec<Dependency>>,
 }
 
 impl Dependencies {
-    pub fn parse(table: &Table, feature_names: &[String]) -> Self {
+    pub fn parse(
+        table: &Table,
+        feature_names: &[String],
+        workspace: Option<&Inheritable>,
+    ) -> Result<Self, String> {
         let base = table
             .iter()
             .filter(|(name, _)| !feature_names.contains(&name.to_string()))
-            .map(|(name, item)| Dependency::parse(name, item.as_value().unwrap()))
-            .collect::<Vec<Dependency>>();
+            .map(|(name, item)| Dependency::parse(name, item, workspace))
+            .collect::<Result<Vec<Dependency>, String>>()?;
 
         let mut features = HashMap::new();
-        feature_names
+        for (name, value) in feature_names
             .iter()
             .filter_map(|feature| table.iter().find(|(name, _)| name == feature))
-            .for_each(|(name, value)| {
-                let feature_table = value.as_table().unwrap();
-                features.insert(
-                    name.to_owned(),
-                    feature_table
-                        .iter()
-                        .map(|(dep_name, item)| {
-                            Dependency::parse(dep_name, item.as_value().unwrap())
-                        })
-                        .collect::<Vec<Dependency>>(),
-                );
-            });
+        {
+            let feature_table = value
+                .as_table()
+                .ok_or_else(|| format!("dependencies.{} must be a table", name))?;
+            features.insert(
+                name.to_owned(),
+                feature_table
+                    .iter()
+                    .map(|(dep_name, item)| Dependency::parse(dep_name, item, workspace))
+                    .collect::<Result<Vec<Dependency>, String>>()?,
+            );
+        }
 
-        Self { base, features }
+        Ok(Self { base, features })
+    }
+
+    /// The dependencies used with `features` enabled
+    pub fn enabled(&self, features: &[String]) -> Vec<Dependency> {
+        let mut dependencies = self.base.clone();
+        features
+            .iter()
+            .filter_map(|feature| self.features.get(feature))
+            .for_each(|feature| dependencies.extend_from_slice(feature));
+        dependencies
     }
 }
 
@@ -116,38 +722,162 @@ impl Dependencies {
 pub struct Dependency {
     pub name: String,
     pub path: Option<PathBuf>,
+    pub git: Option<GitSource>,
+    pub registry: Option<RegistrySource>,
+    /// The versions the leaf may have, wherever it comes from
+    pub version: Option<VersionReq>,
     pub features: Vec<String>,
+    /// Whether the leaf's `default` features are enabled
+    pub default_features: bool,
+    /// Only used once a feature activates it
+    pub optional: bool,
 }
 
 impl Dependency {
-    pub fn parse(name: &str, value: &Value) -> Self {
-        match value {
-            Value::InlineTable(table) => {
-                let path: Option<PathBuf> = table
-                    .get("path")
-                    .map(|item| item.as_str())
-                    .flatten()
-                    .map(|str| Path::new(str).to_owned());
-                let features: Vec<String> = table
-                    .get("features")
-                    .map(|item| item.as_array())
-                    .flatten()
-                    .map(|array| {
-                        array
-                            .iter()
-                            .filter_map(|v| v.as_str())
-                            .map(|str| str.to_owned())
-                            .collect::<Vec<String>>()
-                    })
-                    .unwrap_or_else(Vec::new);
-                Self {
-                    name: name.to_owned(),
-                    path,
-                    features,
-                }
+    /// Parses a dependency, one with `workspace = true` is taken from `[workspace.dependencies]`
+    /// with any `features` it lists added on top
+    pub fn parse(name: &str, item: &Item, workspace: Option<&Inheritable>) -> Result<Self, String> {
+        let table = item.as_table_like().ok_or_else(|| {
+            format!(
+                "dependency {} must be a table, like {} = {{ path = \"...\" }}",
+                name, name
+            )
+        })?;
+        let mut features: Vec<String> = table
+            .get("features")
+            .and_then(parse_string_array)
+            .unwrap_or_default();
+        let flag = |key: &str| table.get(key).and_then(|item| item.as_bool());
+        let optional = flag("optional").unwrap_or(false);
+
+        if is_inherited(item) {
+            let mut dependency =
+                inherit(workspace, &format!("dependency {}", name), |workspace| {
+                    workspace.dependencies.get(name)
+                })?
+                .clone();
+            features.retain(|feature| !dependency.features.contains(feature));
+            dependency.features.append(&mut features);
+            dependency.optional |= optional;
+            return Ok(dependency);
+        }
+
+        let path: Option<PathBuf> = table
+            .get("path")
+            .and_then(|item| item.as_str())
+            .map(|str| Path::new(str).to_owned());
+        let git = Self::parse_git(name, table)?;
+        if path.is_some() && git.is_some() {
+            return Err(format!(
+                "dependency {} can't have both a path and a git repository",
+                name
+            ));
+        }
+        let version = table
+            .get("version")
+            .and_then(|item| item.as_str())
+            .map(|version| {
+                VersionReq::parse(version).map_err(|error| {
+                    format!(
+                        "dependency {} has an invalid version requirement {}: {}",
+                        name, version, error
+                    )
+                })
+            })
+            .transpose()?;
+        let registry = Self::parse_registry(name, table, version.as_ref())?;
+        if registry.is_some() && git.is_some() {
+            return Err(format!(
+                "dependency {} can't come from a registry as well as a git repository",
+                name
+            ));
+        }
+
+        Ok(Self {
+            name: name.to_owned(),
+            path,
+            git,
+            registry,
+            version,
+            features,
+            default_features: flag("default-features").unwrap_or(true),
+            optional,
+        })
+    }
+
+    /// Parses `registry`. Dependencies with only a version come from the default registry
+    fn parse_registry(
+        name: &str,
+        table: &dyn TableLike,
+        version: Option<&VersionReq>,
+    ) -> Result<Option<RegistrySource>, String> {
+        let registry = table.get("registry").and_then(|item| item.as_str());
+        if registry.is_none() && (table.contains_key("path") || table.contains_key("git")) {
+            // A version next to a path or git repository only checks the leaf's version
+            return Ok(None);
+        }
+
+        let requirement = match (version, registry) {
+            (Some(version), _) => version.clone(),
+            (None, Some(_)) => {
+                return Err(format!(
+                    "dependency {} comes from a registry but doesn't say which version, like \
+                     version = \"^1.2\"",
+                    name
+                ))
             }
-            _ => panic!("Teapot doesn't support non table based dependencies"),
+            (None, None) => return Ok(None),
+        };
+
+        Ok(Some(RegistrySource {
+            registry: registry.unwrap_or(DEFAULT_REGISTRY).to_owned(),
+            requirement,
+        }))
+    }
+
+    /// Parses `git` along with the `rev`, `tag` or `branch` to check out
+    fn parse_git(name: &str, table: &dyn TableLike) -> Result<Option<GitSource>, String> {
+        let get = |key: &str| table.get(key).and_then(|item| item.as_str());
+        let references = [
+            get("rev").map(|rev| GitReference::Rev(rev.to_owned())),
+            get("tag").map(|tag| GitReference::Tag(tag.to_owned())),
+            get("branch").map(|branch| GitReference::Branch(branch.to_owned())),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<GitReference>>();
+        if references.len() > 1 {
+            return Err(format!(
+                "dependency {} can only set one of rev, tag and branch",
+                name
+            ));
+        }
+
+        // They're passed to git as arguments, where a leading `-` would be read as an option
+        if let Some(key) = ["git", "rev", "tag", "branch"]
+            .into_iter()
+            .find(|key| get(key).is_some_and(|value| value.starts_with('-')))
+        {
+            return Err(format!(
+                "dependency {}'s {} can't start with `-`, git would read it as an option",
+                name, key
+            ));
         }
+
+        let Some(url) = get("git") else {
+            if !references.is_empty() {
+                return Err(format!(
+                    "dependency {} sets a rev, tag or branch without a git repository",
+                    name
+                ));
+            }
+            return Ok(None);
+        };
+
+        Ok(Some(GitSource {
+            url: url.to_owned(),
+            reference: references.into_iter().next().unwrap_or(GitReference::Head),
+        }))
     }
 }
 
@@ -158,48 +888,70 @@ pub struct Defines {
 }
 
 impl Defines {
-    fn parse_define(name: &str, item: &Item) -> (String, Option<String>) {
-        (
-            name.to_owned(),
-            match item.as_value().unwrap() {
-                Value::String(data) => {
-                    if data.value() == "" {
-                        None
-                    } else {
-                        Some(data.to_string())
-                    }
+    /// Parses a define, one with `workspace = true` is taken from `[workspace.defines]`
+    pub fn parse_define(
+        name: &str,
+        item: &Item,
+        workspace: Option<&Inheritable>,
+    ) -> Result<(String, Option<String>), String> {
+        if is_inherited(item) {
+            let value = inherit(workspace, &format!("define {}", name), |workspace| {
+                workspace.defines.get(name)
+            })?;
+            return Ok((name.to_owned(), value.clone()));
+        }
+
+        let value = match item.as_value() {
+            Some(Value::String(data)) => {
+                if data.value().is_empty() {
+                    None
+                } else {
+                    Some(data.to_string())
                 }
-                Value::Integer(data) => Some(data.to_string()),
-                Value::Float(data) => Some(data.to_string()),
-                Value::Boolean(data) => Some(data.to_string()),
-                _ => panic!("Unsupported define type"),
-            },
-        )
+            }
+            Some(Value::Integer(data)) => Some(data.to_string()),
+            Some(Value::Float(data)) => Some(data.to_string()),
+            Some(Value::Boolean(data)) => Some(data.to_string()),
+            _ => {
+                return Err(format!(
+                    "define {} must be a string, integer, float or boolean",
+                    name
+                ))
+            }
+        };
+
+        Ok((name.to_owned(), value))
     }
 
-    pub fn parse(table: &Table, feature_names: &[String]) -> Self {
+    pub fn parse(
+        table: &Table,
+        feature_names: &[String],
+        workspace: Option<&Inheritable>,
+    ) -> Result<Self, String> {
         let base = table
             .iter()
             .filter(|(name, _)| !feature_names.contains(&name.to_string()))
-            .map(|(name, item)| Self::parse_define(name, item))
-            .collect();
+            .map(|(name, item)| Self::parse_define(name, item, workspace))
+            .collect::<Result<_, String>>()?;
 
         let mut features = HashMap::new();
-        feature_names
+        for (name, item) in feature_names
             .iter()
             .filter_map(|feature| table.iter().find(|(name, _)| name == feature))
-            .for_each(|(name, item)| {
-                features.insert(
-                    name.to_owned(),
-                    item.as_table()
-                        .unwrap()
-                        .iter()
-                        .map(|(define, item)| Self::parse_define(define, item))
-                        .collect(),
-                );
-            });
+        {
+            let feature_table = item
+                .as_table()
+                .ok_or_else(|| format!("defines.{} must be a table", name))?;
+            features.insert(
+                name.to_owned(),
+                feature_table
+                    .iter()
+                    .map(|(define, item)| Self::parse_define(define, item, workspace))
+                    .collect::<Result<_, String>>()?,
+            );
+        }
 
-        Self { base, features }
+        Ok(Self { base, features })
     }
 }
 
@@ -209,8 +961,8 @@ pub struct Libraries {
     pub features: HashMap<String, Vec<String>>,
 }
 
-impl Libraries { 
-    pub fn parse(table: &Table, feature_names: &[String]) -> Self {
+impl Libraries {
+    pub fn parse(table: &Table, feature_names: &[String]) -> Result<Self, String> {
         let base = table
             .iter()
             .filter(|(name, _)| !feature_names.contains(&name.to_string()))
@@ -218,20 +970,315 @@ impl Libraries {
             .collect();
 
         let mut features = HashMap::new();
-        feature_names
+        for (name, item) in feature_names
             .iter()
             .filter_map(|feature| table.iter().find(|(name, _)| name == feature))
-            .for_each(|(name, item)| {
-                features.insert(
-                    name.to_owned(),
-                    item.as_table()
-                        .unwrap()
+        {
+            let feature_table = item
+                .as_table()
+                .ok_or_else(|| format!("libraries.{} must be a table", name))?;
+            features.insert(
+                name.to_owned(),
+                feature_table
+                    .iter()
+                    .map(|(lib, _)| lib.to_owned())
+                    .collect(),
+            );
+        }
+
+        Ok(Self { base, features })
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct ToolchainConfig {
+    pub compiler: Option<String>,
+    pub archiver: Option<String>,
+}
+
+impl ToolchainConfig {
+    pub fn parse(table: &Table) -> Self {
+        Self {
+            compiler: table
+                .get("compiler")
+                .and_then(|item| item.as_str())
+                .map(ToOwned::to_owned),
+            archiver: table
+                .get("archiver")
+                .and_then(|item| item.as_str())
+                .map(ToOwned::to_owned),
+        }
+    }
+}
+
+fn parse_string_array(item: &Item) -> Option<Vec<String>> {
+    Some(
+        item.as_array()?
+            .iter()
+            .filter_map(|v| v.as_str())
+            .map(|str| str.to_owned())
+            .collect(),
+    )
+}
+
+/// A `[profile.<name>]` table, unset fields are inherited
+#[derive(Default, Debug, Clone)]
+pub struct ProfileConfig {
+    pub inherits: Option<String>,
+    pub opt_level: Option<String>,
+    pub debug: Option<bool>,
+    pub cflags: Option<Vec<String>>,
+    pub ldflags: Option<Vec<String>>,
+    pub defines: Option<Vec<(String, Option<String>)>>,
+    pub sanitizers: Option<Vec<String>>,
+}
+
+impl ProfileConfig {
+    pub fn parse(table: &Table) -> Result<Self, String> {
+        Ok(Self {
+            inherits: table
+                .get("inherits")
+                .and_then(|item| item.as_str())
+                .map(ToOwned::to_owned),
+            opt_level: table
+                .get("opt-level")
+                .and_then(|item| match item.as_value()? {
+                    Value::Integer(level) => Some(level.value().to_string()),
+                    Value::String(level) => Some(level.value().to_owned()),
+                    _ => None,
+                }),
+            debug: table.get("debug").and_then(|item| item.as_bool()),
+            cflags: table.get("cflags").and_then(parse_string_array),
+            ldflags: table.get("ldflags").and_then(parse_string_array),
+            defines: table
+                .get("defines")
+                .map(|item| {
+                    item.as_table_like()
+                        .ok_or("profile defines must be a table")?
                         .iter()
-                        .map(|(lib, _)| lib.to_owned())
-                        .collect(),
-                );
-            });
+                        .map(|(name, item)| Defines::parse_define(name, item, None))
+                        .collect::<Result<Vec<_>, String>>()
+                })
+                .transpose()?,
+            sanitizers: table.get("sanitizers").and_then(parse_string_array),
+        })
+    }
+}
+
+pub const DEV_PROFILE: &str = "dev";
+pub const RELEASE_PROFILE: &str = "release";
+
+/// The settings a build actually uses, after inheritance has been applied
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    pub opt_level: String,
+    pub debug: bool,
+    pub cflags: Vec<String>,
+    pub ldflags: Vec<String>,
+    pub defines: Vec<(String, Option<String>)>,
+    pub sanitizers: Vec<String>,
+}
+
+impl Profile {
+    fn builtin(name: &str) -> Option<Self> {
+        let (opt_level, debug) = match name {
+            DEV_PROFILE => ("0", true),
+            RELEASE_PROFILE => ("3", false),
+            _ => return None,
+        };
+
+        Some(Self {
+            name: name.to_owned(),
+            opt_level: opt_level.to_owned(),
+            debug,
+            cflags: Vec::new(),
+            ldflags: Vec::new(),
+            defines: Vec::new(),
+            sanitizers: Vec::new(),
+        })
+    }
+
+    /// Resolves a profile by name, custom profiles inherit from `dev` unless `inherits` says
+    /// otherwise. Returns None if the profile doesn't exist or inherits in a loop
+    pub fn resolve(name: &str, profiles: &HashMap<String, ProfileConfig>) -> Option<Self> {
+        Self::resolve_inner(name, profiles, &mut Vec::new())
+    }
+
+    fn resolve_inner(
+        name: &str,
+        profiles: &HashMap<String, ProfileConfig>,
+        seen: &mut Vec<String>,
+    ) -> Option<Self> {
+        if seen.iter().any(|profile| profile == name) {
+            return None;
+        }
+        seen.push(name.to_owned());
+
+        let config = profiles.get(name);
+        let mut profile = match Self::builtin(name) {
+            Some(profile) => profile,
+            None => {
+                let parent = config?.inherits.as_deref().unwrap_or(DEV_PROFILE);
+                Self::resolve_inner(parent, profiles, seen)?
+            }
+        };
+        profile.name = name.to_owned();
+
+        if let Some(config) = config {
+            if let Some(opt_level) = &config.opt_level {
+                profile.opt_level = opt_level.clone();
+            }
+            if let Some(debug) = config.debug {
+                profile.debug = debug;
+            }
+            if let Some(cflags) = &config.cflags {
+                profile.cflags = cflags.clone();
+            }
+            if let Some(ldflags) = &config.ldflags {
+                profile.ldflags = ldflags.clone();
+            }
+            if let Some(defines) = &config.defines {
+                profile.defines = defines.clone();
+            }
+            if let Some(sanitizers) = &config.sanitizers {
+                profile.sanitizers = sanitizers.clone();
+            }
+        }
+
+        Some(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profiles(manifest: &str) -> HashMap<String, ProfileConfig> {
+        BuildConfig::parse(&manifest.parse().unwrap())
+            .unwrap()
+            .profiles
+    }
+
+    #[test]
+    fn custom_profiles_inherit_from_dev_unless_told_otherwise() {
+        let profiles = profiles(
+            r#"
+[profile.release]
+defines = { NDEBUG = "" }
+
+[profile.asan]
+sanitizers = ["address"]
+
+[profile.bench]
+inherits = "release"
+debug = true
+"#,
+        );
+
+        let asan = Profile::resolve("asan", &profiles).unwrap();
+        assert_eq!(
+            (asan.name.as_str(), asan.opt_level.as_str(), asan.debug),
+            ("asan", "0", true)
+        );
+        assert_eq!(asan.sanitizers, ["address"]);
+
+        let bench = Profile::resolve("bench", &profiles).unwrap();
+        assert_eq!(
+            (bench.name.as_str(), bench.opt_level.as_str(), bench.debug),
+            ("bench", "3", true)
+        );
+        assert_eq!(bench.defines, [("NDEBUG".to_owned(), None)]);
+    }
+
+    #[test]
+    fn rejects_missing_profiles_and_inheritance_loops() {
+        let profiles = profiles(
+            r#"
+[profile.a]
+inherits = "b"
+
+[profile.b]
+inherits = "a"
+
+[profile.c]
+inherits = "missing"
+"#,
+        );
+
+        assert!(Profile::resolve("missing", &profiles).is_none());
+        assert!(Profile::resolve("a", &profiles).is_none());
+        assert!(Profile::resolve("c", &profiles).is_none());
+        assert!(Profile::resolve("release", &profiles).is_some());
+    }
+
+    fn workspace() -> Inheritable {
+        let manifest = r#"
+[workspace]
+members = ["app"]
+
+[workspace.package]
+version = "1.2.0"
+features = ["fast"]
+
+[workspace.dependencies]
+core = { path = "core", features = ["linux"] }
+
+[workspace.defines]
+LOG_LEVEL = 2
+"#;
+        WorkspaceConfig::parse(&manifest.parse().unwrap(), Path::new("/ws"))
+            .unwrap()
+            .unwrap()
+            .inheritable
+    }
+
+    #[test]
+    fn members_inherit_from_the_workspace() {
+        let manifest = r#"
+[package]
+name = "app"
+version = { workspace = true }
+features = { workspace = true }
+
+[dependencies]
+core = { workspace = true, features = ["fast", "linux"] }
+
+[defines]
+LOG_LEVEL = { workspace = true }
+"#;
+        let config = TeaConfig::parse(&manifest.parse().unwrap(), Some(&workspace())).unwrap();
+
+        assert_eq!(config.package.version.to_string(), "1.2.0");
+        assert_eq!(config.package.features, ["fast"]);
+        let core = &config.dependencies.base[0];
+        assert_eq!(core.path.as_deref(), Some(Path::new("/ws/core")));
+        assert_eq!(core.features, ["linux", "fast"]);
+        let (define, value) = &config.defines.base[0];
+        assert_eq!(
+            (define.as_str(), value.as_deref().map(str::trim)),
+            ("LOG_LEVEL", Some("2"))
+        );
+    }
+
+    #[test]
+    fn rejects_inheriting_what_the_workspace_doesnt_set() {
+        let manifest = r#"
+[package]
+name = "app"
+version = "0.1.0"
 
-        Self { base, features }
+[dependencies]
+render = { workspace = true }
+"#;
+        let document = manifest.parse().unwrap();
+        assert_eq!(
+            TeaConfig::parse(&document, Some(&workspace())).unwrap_err(),
+            "dependency render is inherited, but the workspace doesn't set it"
+        );
+        assert_eq!(
+            TeaConfig::parse(&document, None).unwrap_err(),
+            "dependency render is inherited from the workspace, but this leaf isn't in one"
+        );
     }
 }
@@ -0,0 +1,132 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use colored::Colorize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem in a file, pointing at where in the file it is
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub path: PathBuf,
+    pub location: Option<Location>,
+    pub help: Option<String>,
+}
+
+/// The line and column a diagnostic points at, along with the text of that line
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+    pub width: usize,
+    pub source: String,
+}
+
+impl Location {
+    /// Finds the line and column of a byte range in `text`
+    pub fn from_span(text: &str, span: Range<usize>) -> Self {
+        let start = span.start.min(text.len());
+        let line_start = text[..start].rfind('\n').map_or(0, |index| index + 1);
+        let line_end = text[start..]
+            .find('\n')
+            .map_or(text.len(), |index| start + index);
+        let source = text[line_start..line_end].trim_end_matches('\r');
+        let end = span.end.clamp(start, line_end);
+
+        Self {
+            line: text[..start].matches('\n').count() + 1,
+            column: text[line_start..start].chars().count() + 1,
+            width: text[start..end].chars().count().max(1),
+            source: source.to_owned(),
+        }
+    }
+}
+
+impl Diagnostic {
+    pub fn new<T: ToString>(
+        severity: Severity,
+        path: &Path,
+        text: &str,
+        span: Option<Range<usize>>,
+        message: T,
+    ) -> Self {
+        Self {
+            severity,
+            message: message.to_string(),
+            path: path.to_owned(),
+            location: span.map(|span| Location::from_span(text, span)),
+            help: None,
+        }
+    }
+
+    /// Prints the diagnostic to stderr
+    pub fn emit(&self) {
+        let label = match self.severity {
+            Severity::Error => "error:".red().bold(),
+            Severity::Warning => "warning:".yellow().bold(),
+        };
+        eprintln!("{} {}", label, self);
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+
+        match &self.location {
+            Some(location) => {
+                let gutter = " ".repeat(location.line.to_string().len());
+                write!(
+                    f,
+                    "\n{}--> {}:{}:{}",
+                    gutter,
+                    self.path.display(),
+                    location.line,
+                    location.column
+                )?;
+                write!(f, "\n{} |", gutter)?;
+                write!(f, "\n{} | {}", location.line, location.source)?;
+                write!(
+                    f,
+                    "\n{} | {}{}",
+                    gutter,
+                    " ".repeat(location.column - 1),
+                    "^".repeat(location.width)
+                )?;
+                if let Some(help) = &self.help {
+                    write!(f, "\n{} = help: {}", gutter, help)?;
+                }
+            }
+            None => {
+                write!(f, "\n --> {}", self.path.display())?;
+                if let Some(help) = &self.help {
+                    write!(f, "\n  = help: {}", help)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The closest candidate to `word`, if any is close enough to be a likely typo
+pub fn suggest<'a, I>(word: &str, candidates: I) -> Option<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    candidates
+        .into_iter()
+        .map(|candidate| (strsim::jaro_winkler(word, candidate), candidate))
+        .filter(|(similarity, _)| *similarity > 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, candidate)| candidate)
+}
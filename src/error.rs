@@ -0,0 +1,168 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    path::{Path, PathBuf},
+};
+
+use crate::{diagnostic::Diagnostic, graph::DependencyCycle};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can stop teapot. Each kind of failure exits with its own code, these are
+/// documented in the README and must not change:
+///
+/// | Code | Error       |
+/// |------|-------------|
+/// | 1    | Io, Threads |
+/// | 2    | Usage       |
+/// | 3    | Manifest, Invalid |
+/// | 4    | Resolution  |
+/// | 5    | Toolchain   |
+/// | 6    | Compile     |
+/// | 7    | Link        |
+/// | 8    | Test        |
+/// | 9    | Lint        |
+///
+/// `pour` exits with the program's own exit code if it fails
+#[derive(Debug)]
+pub enum Error {
+    /// A file couldn't be read or written
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The threads to build on couldn't be started
+    Threads { count: usize, message: String },
+    /// The command line asked for something that doesn't make sense
+    Usage(String),
+    /// A tea.toml couldn't be read or is invalid
+    Manifest { path: PathBuf, message: String },
+    /// A tea.toml doesn't match the manifest schema
+    Invalid(Vec<Diagnostic>),
+    /// The dependency graph couldn't be resolved
+    Resolution(String),
+    /// A tool couldn't be run, or doesn't support what was asked of it
+    Toolchain { tool: String, message: String },
+    /// A source file failed to compile
+    Compile {
+        source: PathBuf,
+        command: String,
+        output: String,
+    },
+    /// An archive or binary failed to link
+    Link { artifact: String, output: String },
+    /// The test runner of a leaf failed
+    Test { leaf: String, status: String },
+    /// clang-tidy found problems
+    Lint,
+    /// A program run by `pour` failed
+    Run { program: String, code: Option<i32> },
+    /// Several jobs failed at once, reported together
+    Many(Vec<Error>),
+    /// Some combinations of features built by `matrix` failed, with the flags of each
+    Matrix(Vec<(String, Error)>),
+}
+
+impl Error {
+    pub fn manifest<T: ToString>(path: &Path, message: T) -> Self {
+        Self::Manifest {
+            path: path.to_owned(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn toolchain<T: ToString>(tool: &str, message: T) -> Self {
+        Self::Toolchain {
+            tool: tool.to_owned(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Io { .. } | Self::Threads { .. } => 1,
+            Self::Usage(_) => 2,
+            Self::Manifest { .. } | Self::Invalid(_) => 3,
+            Self::Resolution(_) => 4,
+            Self::Toolchain { .. } => 5,
+            Self::Compile { .. } => 6,
+            Self::Link { .. } => 7,
+            Self::Test { .. } => 8,
+            Self::Lint => 9,
+            Self::Run { code, .. } => code.unwrap_or(1),
+            Self::Many(errors) => errors.first().map_or(1, Self::exit_code),
+            Self::Matrix(failures) => failures.first().map_or(1, |(_, error)| error.exit_code()),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Self::Threads { count, message } => {
+                write!(f, "couldn't start {} build threads: {}", count, message)
+            }
+            Self::Usage(message) => write!(f, "{}", message),
+            Self::Manifest { path, message } => write!(f, "{}: {}", path.display(), message),
+            Self::Invalid(diagnostics) => {
+                let messages: Vec<String> = diagnostics.iter().map(ToString::to_string).collect();
+                write!(f, "{}", messages.join("\n\nerror: "))
+            }
+            Self::Resolution(message) => write!(f, "{}", message.trim_end()),
+            Self::Toolchain { tool, message } => write!(f, "{}: {}", tool, message),
+            Self::Compile {
+                source,
+                command,
+                output,
+            } => write!(
+                f,
+                "{} failed to compile\n  {}\n\n{}",
+                source.display(),
+                command,
+                output.trim_end()
+            ),
+            Self::Link { artifact, output } => {
+                write!(f, "{} failed to link\n\n{}", artifact, output.trim_end())
+            }
+            Self::Test { leaf, status } => write!(f, "tests for {} failed ({})", leaf, status),
+            Self::Lint => write!(f, "clang-tidy found problems"),
+            Self::Run { program, code } => match code {
+                Some(code) => write!(f, "{} exited with code {}", program, code),
+                None => write!(f, "{} was terminated by a signal", program),
+            },
+            Self::Many(errors) => {
+                let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                write!(f, "{}", messages.join("\n\nerror: "))
+            }
+            Self::Matrix(failures) => {
+                let messages: Vec<String> = failures
+                    .iter()
+                    .map(|(combination, error)| format!("{} failed: {}", combination, error))
+                    .collect();
+                write!(f, "{}", messages.join("\n\nerror: "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<DependencyCycle> for Error {
+    fn from(cycle: DependencyCycle) -> Self {
+        Self::Resolution(cycle.to_string())
+    }
+}
+
+/// Attaches the path being worked on to IO errors
+pub trait IoContext<T> {
+    fn with_path(self, path: &Path) -> Result<T>;
+}
+
+impl<T> IoContext<T> for std::io::Result<T> {
+    fn with_path(self, path: &Path) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            path: path.to_owned(),
+            source,
+        })
+    }
+}
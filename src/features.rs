@@ -0,0 +1,373 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::{FeatureValue, TeaConfig, BASE_FEATURES, DEFAULT_FEATURE},
+    diagnostic,
+    error::{Error, Result},
+    graph::canonical_path,
+    Leaf,
+};
+
+/// The features asked for from each leaf across the graph. A leaf depended on with different
+/// features is built once with all of them, which takes loading the graph again when a leaf
+/// already loaded turns out to need more
+#[derive(Debug, Default)]
+pub struct FeatureUnion {
+    features: HashMap<PathBuf, Vec<String>>,
+    /// The leaves asked for during the current attempt at loading the graph
+    requested: HashSet<PathBuf>,
+    changed: bool,
+}
+
+impl FeatureUnion {
+    /// Forgets which leaves the last attempt at loading the graph asked for, keeping their
+    /// features
+    pub fn start(&mut self) {
+        self.requested.clear();
+        self.changed = false;
+    }
+
+    /// Whether a leaf was asked for more features after it was loaded, in which case the graph
+    /// has to be loaded again
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    /// Adds `features` to those asked for from the leaf in `path`, returning all of them
+    pub fn request(&mut self, path: &Path, features: &[String]) -> Vec<String> {
+        let path = canonical_path(path);
+        let union = self.features.entry(path.clone()).or_default();
+        let added = features
+            .iter()
+            .filter(|feature| !union.contains(feature))
+            .cloned()
+            .collect::<Vec<String>>();
+
+        if !self.requested.insert(path) && !added.is_empty() {
+            self.changed = true;
+        }
+        union.extend(added);
+        union.clone()
+    }
+}
+
+/// What a leaf is built with once everything its features enable is followed
+#[derive(Debug, Default)]
+pub struct ResolvedFeatures {
+    pub enabled: Vec<String>,
+    /// The features each dependency gets from `"dep/feature"`, by dependency name
+    pub dependencies: HashMap<String, Vec<String>>,
+    /// The optional dependencies activated
+    pub optional: Vec<String>,
+}
+
+impl ResolvedFeatures {
+    /// Follows what each of `requested` enables in the leaf's `[features]`
+    pub fn resolve(config: &TeaConfig, requested: &[String]) -> Result<Self> {
+        let known = BASE_FEATURES
+            .iter()
+            .map(ToString::to_string)
+            .chain(config.package.features.iter().cloned())
+            .collect::<Vec<String>>();
+
+        let mut resolved = Self::default();
+        let mut pending = requested.to_owned();
+        let mut defaults = false;
+        while let Some(feature) = pending.pop() {
+            if feature == DEFAULT_FEATURE {
+                if !defaults {
+                    defaults = true;
+                    pending.extend_from_slice(&config.features.default);
+                }
+                continue;
+            }
+            if resolved.enabled.contains(&feature) {
+                continue;
+            }
+            if !known.contains(&feature) {
+                let mut message = format!(
+                    "{} is depended on with feature {}, which it doesn't have",
+                    config.package.name, feature
+                );
+                if let Some(suggestion) =
+                    diagnostic::suggest(&feature, known.iter().map(String::as_str))
+                {
+                    message.push_str(&format!(", did you mean {}?", suggestion));
+                }
+                return Err(Error::Resolution(message));
+            }
+
+            for value in config.features.enables(&feature) {
+                match value {
+                    FeatureValue::Feature(other) => pending.push(other.clone()),
+                    FeatureValue::Dependency { name, feature } => {
+                        resolved.activate(name);
+                        let features = resolved.dependencies.entry(name.clone()).or_default();
+                        if !features.contains(feature) {
+                            features.push(feature.clone());
+                        }
+                    }
+                    FeatureValue::Optional(name) => resolved.activate(name),
+                }
+            }
+            resolved.enabled.push(feature);
+        }
+
+        Ok(resolved)
+    }
+
+    fn activate(&mut self, dependency: &str) {
+        if !self.optional.iter().any(|name| name == dependency) {
+            self.optional.push(dependency.to_owned());
+        }
+    }
+}
+
+/// Fails if the features a leaf ended up with break one of its `[constraints]`
+pub fn check_leaf_constraints(leaf: &Leaf) -> Result<()> {
+    let enabled = leaf
+        .features
+        .iter()
+        .filter(|feature| feature.enabled)
+        .map(|feature| feature.name.clone())
+        .collect::<Vec<String>>();
+    check_constraints(&leaf.config, &enabled)
+}
+
+/// Fails if `enabled` breaks one of the leaf's `[constraints]`
+pub fn check_constraints(config: &TeaConfig, enabled: &[String]) -> Result<()> {
+    let name = &config.package.name;
+    let enabled = |feature: &String| enabled.contains(feature);
+    let constraints = &config.constraints;
+
+    for group in &constraints.conflicts {
+        let conflicting = group
+            .iter()
+            .filter(|feature| enabled(feature))
+            .cloned()
+            .collect::<Vec<String>>();
+        if conflicting.len() > 1 {
+            let mut message = format!(
+                "{} is built with {}, which conflict",
+                name,
+                list(&conflicting, "and")
+            );
+            if group.len() > conflicting.len() {
+                message.push_str(&format!(
+                    ", only one of {} can be enabled",
+                    list(group, "and")
+                ));
+            }
+            return Err(Error::Resolution(message));
+        }
+    }
+
+    for group in &constraints.one_of {
+        let chosen = group
+            .iter()
+            .filter(|feature| enabled(feature))
+            .cloned()
+            .collect::<Vec<String>>();
+        let message = match chosen.as_slice() {
+            [_] => continue,
+            [] => format!(
+                "{} is built with none of {}, one of them has to be enabled",
+                name,
+                list(group, "or")
+            ),
+            _ => format!(
+                "{} is built with {}, but exactly one of {} has to be enabled",
+                name,
+                list(&chosen, "and"),
+                list(group, "or")
+            ),
+        };
+        return Err(Error::Resolution(message));
+    }
+
+    for (feature, requirements) in constraints.requires.iter() {
+        if !enabled(feature) {
+            continue;
+        }
+        if let Some(requirement) = requirements
+            .iter()
+            .find(|alternatives| !alternatives.iter().any(enabled))
+        {
+            return Err(Error::Resolution(format!(
+                "{} is built with {}, which requires {}",
+                name,
+                feature,
+                list(requirement, "or")
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists features like `a, b and c`
+fn list(features: &[String], conjunction: &str) -> String {
+    match features {
+        [] => String::new(),
+        [feature] => feature.clone(),
+        [rest @ .., last] => format!("{} {} {}", rest.join(", "), conjunction, last),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"
+[package]
+name = "app"
+version = "0.1.0"
+features = ["gui", "png", "fast", "tiny"]
+
+[features]
+default = ["gui", "fast"]
+gui = ["render/wayland", "png"]
+png = ["dep:libpng"]
+tiny = []
+
+[dependencies]
+render = { path = "../render" }
+libpng = { path = "../libpng", optional = true }
+"#;
+
+    fn config(manifest: &str) -> TeaConfig {
+        TeaConfig::parse(&manifest.parse().unwrap(), None).unwrap()
+    }
+
+    fn strings(features: &[&str]) -> Vec<String> {
+        features.iter().map(ToString::to_string).collect()
+    }
+
+    fn resolve(features: &[&str]) -> ResolvedFeatures {
+        ResolvedFeatures::resolve(&config(MANIFEST), &strings(features)).unwrap()
+    }
+
+    fn sorted(mut features: Vec<String>) -> Vec<String> {
+        features.sort();
+        features
+    }
+
+    #[test]
+    fn follows_default_features_transitively() {
+        let resolved = resolve(&["default"]);
+        assert_eq!(sorted(resolved.enabled), strings(&["fast", "gui", "png"]));
+        assert_eq!(
+            resolved.dependencies,
+            HashMap::from([("render".to_owned(), strings(&["wayland"]))])
+        );
+        assert_eq!(sorted(resolved.optional), strings(&["libpng", "render"]));
+    }
+
+    #[test]
+    fn activates_optional_dependencies_only_when_asked_for() {
+        let resolved = resolve(&["tiny", "linux"]);
+        assert_eq!(sorted(resolved.enabled), strings(&["linux", "tiny"]));
+        assert!(resolved.dependencies.is_empty());
+        assert!(resolved.optional.is_empty());
+
+        let resolved = resolve(&["png"]);
+        assert_eq!(resolved.enabled, strings(&["png"]));
+        assert_eq!(resolved.optional, strings(&["libpng"]));
+    }
+
+    #[test]
+    fn rejects_unknown_features() {
+        let error = ResolvedFeatures::resolve(&config(MANIFEST), &strings(&["gu"])).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "app is depended on with feature gu, which it doesn't have, did you mean gui?"
+        );
+    }
+
+    #[test]
+    fn asks_for_the_graph_again_when_a_loaded_leaf_gains_features() {
+        let path = Path::new("render");
+        let mut union = FeatureUnion::default();
+        union.start();
+        assert_eq!(union.request(path, &strings(&["x11"])), strings(&["x11"]));
+        assert_eq!(union.request(path, &strings(&["x11"])), strings(&["x11"]));
+        assert!(!union.changed());
+        assert_eq!(
+            union.request(path, &strings(&["wayland"])),
+            strings(&["x11", "wayland"])
+        );
+        assert!(union.changed());
+
+        // The next attempt starts out with everything asked for so far
+        union.start();
+        assert_eq!(
+            union.request(path, &strings(&["x11"])),
+            strings(&["x11", "wayland"])
+        );
+        assert!(!union.changed());
+    }
+
+    const CONSTRAINED: &str = r#"
+[package]
+name = "render"
+version = "0.1.0"
+features = ["x11", "wayland", "gl", "vulkan", "software", "egl"]
+
+[constraints]
+conflicts = [["gl", "vulkan", "software"]]
+one-of = [["x11", "wayland"]]
+requires = { vulkan = ["wayland"], egl = ["x11|wayland", "gl"] }
+"#;
+
+    fn check(features: &[&str]) -> std::result::Result<(), String> {
+        check_constraints(&config(CONSTRAINED), &strings(features)).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn accepts_features_that_meet_the_constraints() {
+        assert_eq!(check(&["x11", "gl"]), Ok(()));
+        assert_eq!(check(&["wayland", "vulkan"]), Ok(()));
+    }
+
+    #[test]
+    fn reports_conflicting_features() {
+        assert_eq!(
+            check(&["x11", "gl", "vulkan"]),
+            Err("render is built with gl and vulkan, which conflict, \
+                 only one of gl, vulkan and software can be enabled"
+                .to_owned())
+        );
+    }
+
+    #[test]
+    fn reports_groups_where_not_exactly_one_feature_is_enabled() {
+        assert_eq!(
+            check(&["gl"]),
+            Err(
+                "render is built with none of x11 or wayland, one of them has to be enabled"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            check(&["x11", "wayland"]),
+            Err("render is built with x11 and wayland, \
+                 but exactly one of x11 or wayland has to be enabled"
+                .to_owned())
+        );
+    }
+
+    #[test]
+    fn reports_missing_requirements() {
+        assert_eq!(
+            check(&["x11", "vulkan"]),
+            Err("render is built with vulkan, which requires wayland".to_owned())
+        );
+        assert_eq!(
+            check(&["x11", "egl"]),
+            Err("render is built with egl, which requires gl".to_owned())
+        );
+    }
+}
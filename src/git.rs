@@ -0,0 +1,353 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    path::{Path, PathBuf},
+};
+
+use colored::Colorize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    compiler::to_hex,
+    error::{Error, IoContext, Result},
+    layout,
+    lockfile::LockMode,
+};
+
+/// Marks a checkout as complete, so one interrupted halfway is checked out again
+const CHECKOUT_DONE: &str = ".tpot-checkout";
+
+/// A dependency fetched from a git repository, any URL git itself understands can be used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitSource {
+    pub url: String,
+    pub reference: GitReference,
+}
+
+/// Which commit of the repository to use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GitReference {
+    /// The repository's default branch
+    Head,
+    Branch(String),
+    Tag(String),
+    Rev(String),
+}
+
+impl Display for GitSource {
+    /// The source as it appears in tea.lock, without the commit, like `git+<url>?tag=v1.0`
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "git+{}", self.url)?;
+        match &self.reference {
+            GitReference::Head => Ok(()),
+            GitReference::Branch(branch) => write!(f, "?branch={}", branch),
+            GitReference::Tag(tag) => write!(f, "?tag={}", tag),
+            GitReference::Rev(rev) => write!(f, "?rev={}", rev),
+        }
+    }
+}
+
+/// A commit of a git dependency, checked out into the cache
+#[derive(Debug, Clone)]
+pub struct GitCheckout {
+    pub source: GitSource,
+    pub commit: String,
+    pub path: PathBuf,
+}
+
+/// Fetches git dependencies into `<tpot home>/git`, with a bare clone of each repository in
+/// `db` and a checkout of each commit used in `checkouts`. Commits pinned by tea.lock are used
+/// rather than fetching the latest commit of a branch
+#[derive(Debug, Default)]
+pub struct GitCache {
+    pub mode: LockMode,
+    /// The commit locked for each source
+    pub pinned: HashMap<String, String>,
+    checkouts: HashMap<String, GitCheckout>,
+}
+
+impl GitCache {
+    /// Checks out the commit `source` points at, fetching it first if it isn't in the cache
+    pub fn checkout(&mut self, name: &str, source: &GitSource) -> Result<GitCheckout> {
+        let git = layout::tpot_home()?.join("git");
+        self.checkout_in(&git, name, source)
+    }
+
+    /// Like [`GitCache::checkout`], with the cache in `git` rather than the tpot home
+    fn checkout_in(&mut self, git: &Path, name: &str, source: &GitSource) -> Result<GitCheckout> {
+        let key = source.to_string();
+        if let Some(checkout) = self.checkouts.get(&key) {
+            return Ok(checkout.clone());
+        }
+
+        let directory = format!("{}-{}", name, short_hash(&source.url));
+        let database = git.join("db").join(&directory);
+        let commit = self.resolve(&database, source)?;
+        let path = git.join("checkouts").join(&directory).join(&commit[..12]);
+
+        if !path.join(CHECKOUT_DONE).is_file() {
+            if path.exists() {
+                std::fs::remove_dir_all(&path).with_path(&path)?;
+            }
+            run_git(
+                &[
+                    "clone",
+                    "--quiet",
+                    "--shared",
+                    "--no-checkout",
+                    "--",
+                    &database.to_string_lossy(),
+                    &path.to_string_lossy(),
+                ],
+                &format!("check out {}", source.url),
+            )?;
+            run_git(
+                &[
+                    "-C",
+                    &path.to_string_lossy(),
+                    "checkout",
+                    "--quiet",
+                    "--detach",
+                    &commit,
+                ],
+                &format!("check out {}", source.url),
+            )?;
+            std::fs::write(path.join(CHECKOUT_DONE), &commit).with_path(&path)?;
+        }
+
+        let checkout = GitCheckout {
+            source: source.clone(),
+            commit,
+            path,
+        };
+        self.checkouts.insert(key, checkout.clone());
+        Ok(checkout)
+    }
+
+    /// Finds the commit to use, fetching only when the commit or reference isn't in the cache
+    /// already or could have moved
+    fn resolve(&self, database: &Path, source: &GitSource) -> Result<String> {
+        if !database.exists() {
+            run_git(
+                &[
+                    "init",
+                    "--quiet",
+                    "--bare",
+                    "--",
+                    &database.to_string_lossy(),
+                ],
+                &format!("create the git cache for {}", source.url),
+            )?;
+        }
+
+        let wanted = match self.pinned.get(&source.to_string()) {
+            Some(commit) => commit.clone(),
+            None => match &source.reference {
+                GitReference::Head => "refs/tpot/HEAD".to_owned(),
+                GitReference::Branch(branch) => format!("refs/heads/{}", branch),
+                GitReference::Tag(tag) => format!("refs/tags/{}", tag),
+                GitReference::Rev(rev) => rev.clone(),
+            },
+        };
+        // Branches move, so they're always fetched unless a commit is pinned
+        let moves = !self.pinned.contains_key(&source.to_string())
+            && matches!(
+                source.reference,
+                GitReference::Head | GitReference::Branch(_)
+            );
+
+        let frozen = self.mode == LockMode::Frozen;
+        if !frozen && (moves || find_commit(database, &wanted).is_none()) {
+            fetch(database, &source.url)?;
+        }
+
+        find_commit(database, &wanted).ok_or_else(|| {
+            if frozen {
+                Error::Resolution(format!(
+                    "{} isn't in the git cache, and --frozen doesn't allow fetching it",
+                    source
+                ))
+            } else {
+                Error::Resolution(format!("couldn't find {} in {}", wanted, source.url))
+            }
+        })
+    }
+}
+
+fn fetch(database: &Path, url: &str) -> Result<()> {
+    println!(
+        "\r{:13} {} {}",
+        String::new(),
+        "Fetching".green().bold(),
+        url
+    );
+    run_git(
+        &[
+            "--git-dir",
+            &database.to_string_lossy(),
+            "fetch",
+            "--quiet",
+            "--force",
+            "--",
+            url,
+            "+HEAD:refs/tpot/HEAD",
+            "+refs/heads/*:refs/heads/*",
+            "+refs/tags/*:refs/tags/*",
+        ],
+        &format!("fetch {}", url),
+    )
+}
+
+/// The full hash of the commit `reference` names in the database, if it's there
+fn find_commit(database: &Path, reference: &str) -> Option<String> {
+    let output = duct::cmd!(
+        "git",
+        "--git-dir",
+        database,
+        "rev-parse",
+        "--verify",
+        "--quiet",
+        format!("{}^{{commit}}", reference)
+    )
+    .stdout_capture()
+    .stderr_null()
+    .unchecked()
+    .run()
+    .ok()?;
+
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Runs git, failing with its output if it doesn't succeed. `action` describes what it was doing
+fn run_git(args: &[&str], action: &str) -> Result<()> {
+    let output = duct::cmd("git", args)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .map_err(|error| Error::toolchain("git", format!("couldn't run: {}", error)))?;
+    if !output.status.success() {
+        return Err(Error::Resolution(format!(
+            "couldn't {}\n\n{}",
+            action,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Keeps the caches of repositories with the same name but different URLs apart
+fn short_hash(url: &str) -> String {
+    to_hex(&Sha256::digest(url.as_bytes())[..4])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::TeaConfig, lockfile, Leaf, Source};
+
+    /// Runs git in `repository`, returning what it prints
+    fn git(repository: &Path, args: &[&str]) -> String {
+        let output = duct::cmd("git", args)
+            .dir(repository)
+            .env("GIT_AUTHOR_NAME", "tpot")
+            .env("GIT_AUTHOR_EMAIL", "tpot@example.com")
+            .env("GIT_COMMITTER_NAME", "tpot")
+            .env("GIT_COMMITTER_EMAIL", "tpot@example.com")
+            .stdout_capture()
+            .run()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    }
+
+    /// Commits a tea.toml with `version`, returning the commit
+    fn commit(repository: &Path, version: &str) -> String {
+        let manifest = format!("[package]\nname = \"core\"\nversion = \"{}\"\n", version);
+        std::fs::write(repository.join("tea.toml"), manifest).unwrap();
+        git(repository, &["add", "tea.toml"]);
+        git(repository, &["commit", "--quiet", "-m", version]);
+        git(repository, &["rev-parse", "HEAD"])
+    }
+
+    fn version(checkout: &GitCheckout) -> String {
+        let manifest = std::fs::read_to_string(checkout.path.join("tea.toml")).unwrap();
+        let config = TeaConfig::parse(&manifest.parse().unwrap(), None).unwrap();
+        config.package.version.to_string()
+    }
+
+    #[test]
+    fn checks_out_tags_branches_and_revs_from_file_urls() {
+        let root = std::env::temp_dir().join(format!("tpot-git-{}", std::process::id()));
+        let repository = root.join("core");
+        std::fs::create_dir_all(&repository).unwrap();
+        git(&repository, &["init", "--quiet"]);
+        let tagged = commit(&repository, "1.0.0");
+        git(&repository, &["tag", "v1.0"]);
+        let head = commit(&repository, "1.1.0");
+        git(&repository, &["checkout", "--quiet", "-b", "next"]);
+        let branch = commit(&repository, "2.0.0");
+        git(&repository, &["checkout", "--quiet", "-"]);
+
+        let url = format!("file://{}", repository.display());
+        let source = |reference| GitSource {
+            url: url.clone(),
+            reference,
+        };
+        let cache_directory = root.join("cache");
+        let mut cache = GitCache::default();
+        let checkout = |cache: &mut GitCache, reference| {
+            cache
+                .checkout_in(&cache_directory, "core", &source(reference))
+                .unwrap()
+        };
+
+        let tag = checkout(&mut cache, GitReference::Tag("v1.0".to_owned()));
+        assert_eq!(
+            (tag.commit.as_str(), version(&tag).as_str()),
+            (tagged.as_str(), "1.0.0")
+        );
+        let next = checkout(&mut cache, GitReference::Branch("next".to_owned()));
+        assert_eq!(
+            (next.commit.as_str(), version(&next).as_str()),
+            (branch.as_str(), "2.0.0")
+        );
+        let rev = checkout(&mut cache, GitReference::Rev(head[..12].to_owned()));
+        assert_eq!(
+            (rev.commit.as_str(), version(&rev).as_str()),
+            (head.as_str(), "1.1.0")
+        );
+        let default = checkout(&mut cache, GitReference::Head);
+        assert_eq!(default.commit, head);
+
+        // A commit pinned by tea.lock is kept even though the branch has moved on
+        commit(&repository, "1.2.0");
+        let mut pinned = GitCache::default();
+        pinned
+            .pinned
+            .insert(source(GitReference::Head).to_string(), head.clone());
+        assert_eq!(checkout(&mut pinned, GitReference::Head).commit, head);
+
+        // The exact commit ends up in the lockfile
+        let manifest = "[package]\nname = \"core\"\nversion = \"1.0.0\"\n";
+        let leaf = Leaf {
+            config: TeaConfig::parse(&manifest.parse().unwrap(), None).unwrap(),
+            dependencies: Vec::new(),
+            features: Vec::new(),
+            path: tag.path.clone(),
+            source: Source::Git(tag),
+            defines: Vec::new(),
+            libraries: Vec::new(),
+            unit: "core".to_owned(),
+        };
+        assert_eq!(
+            lockfile::source(&leaf, &root),
+            format!("git+{}?tag=v1.0#{}", url, tagged)
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,276 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    ops::Index,
+    path::{Component, Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{compiler::to_hex, Leaf};
+
+pub type LeafId = usize;
+
+/// Identifies a single build of a leaf, the same leaf with a different feature set is a
+/// different build
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LeafKey {
+    pub path: PathBuf,
+    pub features: Vec<String>,
+}
+
+impl LeafKey {
+    pub fn new(path: &Path, features: &[String]) -> Self {
+        let path = canonical_path(path);
+        let mut features = features.to_owned();
+        features.sort();
+        features.dedup();
+
+        Self { path, features }
+    }
+
+    /// A short stable hash, used to keep the artifacts of different builds of a leaf apart
+    pub fn hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.path.display().to_string().as_bytes());
+        self.features.iter().for_each(|feature| {
+            hasher.update([0]);
+            hasher.update(feature.as_bytes());
+        });
+
+        to_hex(&hasher.finalize()[..4])
+    }
+}
+
+/// Resolves `path` so the same leaf is recognised however it's referred to, the current directory
+/// is given as an empty path
+pub fn canonical_path(path: &Path) -> PathBuf {
+    let path = if path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        path
+    };
+
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
+
+/// Removes `.` and `..` components without touching the filesystem, so a leaf has the same path
+/// whichever leaf depending on it was loaded first
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+/// `path` relative to `base`, going up with `..` where it isn't inside `base`. Both should be
+/// canonical
+pub fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    base.components()
+        .skip(common)
+        .for_each(|_| relative.push(".."));
+    path.components()
+        .skip(common)
+        .for_each(|component| relative.push(component));
+
+    relative
+}
+
+/// A chain of leaves where the last leaf depends on the first
+#[derive(Debug)]
+pub struct DependencyCycle {
+    /// The name and directory of each leaf in the chain, starting and ending with the same leaf
+    pub chain: Vec<(String, PathBuf)>,
+}
+
+impl Display for DependencyCycle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Dependency cycle detected:")?;
+        self.chain
+            .iter()
+            .enumerate()
+            .try_for_each(|(index, (name, path))| {
+                let arrow = if index == 0 { "   " } else { "-> " };
+                writeln!(
+                    f,
+                    "  {}{} ({})",
+                    arrow,
+                    name,
+                    path.join("tea.toml").display()
+                )
+            })
+    }
+}
+
+/// Every leaf in a build, each unique build of a leaf appears exactly once
+#[derive(Debug, Default)]
+pub struct LeafGraph {
+    leaves: Vec<Leaf>,
+    keys: HashMap<LeafKey, LeafId>,
+    roots: Vec<LeafId>,
+}
+
+impl LeafGraph {
+    pub fn get(&self, key: &LeafKey) -> Option<LeafId> {
+        self.keys.get(key).copied()
+    }
+
+    pub fn insert(&mut self, key: LeafKey, leaf: Leaf) -> LeafId {
+        let id = self.leaves.len();
+        self.leaves.push(leaf);
+        self.keys.insert(key, id);
+
+        id
+    }
+
+    pub fn add_root(&mut self, root: LeafId) {
+        if !self.roots.contains(&root) {
+            self.roots.push(root);
+        }
+    }
+
+    /// The leaves that were asked for, rather than pulled in as dependencies
+    pub fn roots(&self) -> &[LeafId] {
+        &self.roots
+    }
+
+    pub fn leaves(&self) -> &[Leaf] {
+        &self.leaves
+    }
+
+    /// Every leaf reachable from `root` with dependents before their dependencies, the order
+    /// static libraries have to be given to the linker in
+    pub fn link_order(&self, root: LeafId) -> Vec<LeafId> {
+        let mut visited = vec![false; self.leaves.len()];
+        let mut order = Vec::new();
+        self.visit(root, &mut visited, &mut order);
+        order.reverse();
+
+        order
+    }
+
+    fn visit(&self, id: LeafId, visited: &mut [bool], order: &mut Vec<LeafId>) {
+        if visited[id] {
+            return;
+        }
+        visited[id] = true;
+
+        self[id]
+            .dependencies
+            .iter()
+            .for_each(|dependency| self.visit(*dependency, visited, order));
+        order.push(id);
+    }
+}
+
+impl Index<LeafId> for LeafGraph {
+    type Output = Leaf;
+
+    fn index(&self, id: LeafId) -> &Self::Output {
+        &self.leaves[id]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cli::{FeaturesData, SelectionData},
+        config::TeaConfig,
+        error::Error,
+        workspace::Project,
+        Source,
+    };
+
+    fn leaf(name: &str, dependencies: &[LeafId]) -> Leaf {
+        let manifest = format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\n", name);
+        Leaf {
+            config: TeaConfig::parse(&manifest.parse().unwrap(), None).unwrap(),
+            dependencies: dependencies.to_owned(),
+            features: Vec::new(),
+            path: PathBuf::from(name),
+            source: Source::Path,
+            defines: Vec::new(),
+            libraries: Vec::new(),
+            unit: name.to_owned(),
+        }
+    }
+
+    #[test]
+    fn link_order_of_a_diamond() {
+        // app depends on left and right, which both depend on core
+        let mut leaves = LeafGraph::default();
+        let core = leaves.insert(LeafKey::new(Path::new("core"), &[]), leaf("core", &[]));
+        let left = leaves.insert(LeafKey::new(Path::new("left"), &[]), leaf("left", &[core]));
+        let right = leaves.insert(
+            LeafKey::new(Path::new("right"), &[]),
+            leaf("right", &[core]),
+        );
+        let app = leaves.insert(
+            LeafKey::new(Path::new("app"), &[]),
+            leaf("app", &[left, right]),
+        );
+
+        assert_eq!(leaves.link_order(app), vec![app, right, left, core]);
+        assert_eq!(leaves.link_order(left), vec![left, core]);
+    }
+
+    #[test]
+    fn reports_the_path_of_a_cycle() {
+        let root = std::env::temp_dir().join(format!("tpot-cycle-{}", std::process::id()));
+        for (name, dependency) in [("app", "a"), ("a", "b"), ("b", "a")] {
+            let directory = root.join(name);
+            std::fs::create_dir_all(&directory).unwrap();
+            std::fs::write(
+                directory.join("tea.toml"),
+                format!(
+                    "[package]\nname = \"{}\"\nversion = \"0.1.0\"\n\n[dependencies]\n\
+                     {} = {{ path = \"../{}\" }}\n",
+                    name, dependency, dependency
+                ),
+            )
+            .unwrap();
+        }
+
+        let selection = SelectionData {
+            packages: Vec::new(),
+            workspace: false,
+        };
+        let app = root.join("app");
+        let mut project = Project::load_in(&app, &selection).unwrap();
+        let error = Leaf::load_graph(&mut project, &[app], &FeaturesData::default()).unwrap_err();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let Error::Resolution(message) = error else {
+            panic!("expected a resolution error, got {:?}", error);
+        };
+        let root = canonical_path(&std::env::temp_dir())
+            .join(format!("tpot-cycle-{}", std::process::id()));
+        let expected = format!(
+            "Dependency cycle detected:\n     a ({0})\n  -> b ({1})\n  -> a ({0})\n",
+            root.join("a").join("tea.toml").display(),
+            root.join("b").join("tea.toml").display()
+        );
+        assert_eq!(message, expected);
+    }
+}
@@ -0,0 +1,116 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    compdb::COMPILE_COMMANDS,
+    error::{Error, Result},
+};
+
+pub const DEFAULT_TARGET_DIR: &str = "target";
+pub const TARGET_DIR_ENV: &str = "TPOT_TARGET_DIR";
+pub const HOME_ENV: &str = "TPOT_HOME";
+/// Where `tpot package` writes archives in the target directory, next to the profiles, so no
+/// profile can be named after it
+pub const PACKAGE_DIRECTORY: &str = "package";
+/// The directories next to the binaries in a profile's directory, which leaves can't be named
+/// after
+pub const PROFILE_SUBDIRECTORIES: &[&str] = &["deps", "objects"];
+
+/// Where a build puts its artifacts:
+///
+/// ```text
+/// target/<profile>/<name>                       each final binary
+/// target/<profile>/objects/                     objects of the entry points
+/// target/<profile>/deps/<unit>/objects/         objects of each leaf
+/// target/<profile>/deps/<unit>/lib<leaf>.a      archive of each leaf
+/// target/<profile>/deps/<unit>/main.c           generated entry point of each binary
+/// target/package/<name>-<version>.tar.gz        archive made by `tpot package`
+/// target/package/<name>-<version>/              the archive unpacked, to check it builds
+/// target/compile_commands.json                  compiler invocations of the last build
+/// ```
+///
+/// Where a unit is the leaf's name and a hash of its path and features, so building the same leaf
+/// with different features doesn't clash:
+///
+/// ```text
+/// target/dev/deps/raylib-1a2b3c4d/libraylib.a
+/// ```
+#[derive(Debug, Clone)]
+pub struct Layout {
+    target_directory: PathBuf,
+    profile_directory: PathBuf,
+}
+
+impl Layout {
+    /// Uses `--target-dir`, then `TPOT_TARGET_DIR`, then `target` in the project root
+    pub fn resolve(flag: Option<&Path>, project_root: &Path, profile: &str) -> Self {
+        let target_directory = flag
+            .map(ToOwned::to_owned)
+            .or_else(|| {
+                std::env::var_os(TARGET_DIR_ENV)
+                    .filter(|dir| !dir.is_empty())
+                    .map(PathBuf::from)
+            })
+            .unwrap_or_else(|| project_root.join(DEFAULT_TARGET_DIR));
+
+        Self {
+            profile_directory: target_directory.join(profile),
+            target_directory,
+        }
+    }
+
+    pub fn target_directory(&self) -> &Path {
+        &self.target_directory
+    }
+
+    /// The directory holding the final binary and generated entry point
+    pub fn profile_directory(&self) -> &Path {
+        &self.profile_directory
+    }
+
+    /// The directory holding a single leaf's objects and archive
+    pub fn leaf_directory(&self, unit: &str) -> PathBuf {
+        self.profile_directory.join("deps").join(unit)
+    }
+
+    pub fn library(&self, unit: &str, name: &str) -> PathBuf {
+        self.leaf_directory(unit).join(format!("lib{}.a", name))
+    }
+
+    pub fn binary(&self, name: &str) -> PathBuf {
+        self.profile_directory.join(name)
+    }
+
+    /// Where every build writes the compile commands of its graph, for clangd and other tools
+    pub fn compile_commands(&self) -> PathBuf {
+        self.target_directory.join(COMPILE_COMMANDS)
+    }
+
+    /// Where packaged leaves are written, shared by every profile
+    pub fn package_directory(&self) -> PathBuf {
+        self.target_directory.join(PACKAGE_DIRECTORY)
+    }
+
+    pub fn entry_point(&self, unit: &str) -> PathBuf {
+        self.leaf_directory(unit).join("main.c")
+    }
+}
+
+/// Where teapot keeps what it shares between projects, like fetched dependencies. `TPOT_HOME`,
+/// or `.tpot` in the user's home directory
+pub fn tpot_home() -> Result<PathBuf> {
+    std::env::var_os(HOME_ENV)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME")
+                .or_else(|| std::env::var_os("USERPROFILE"))
+                .filter(|dir| !dir.is_empty())
+                .map(|home| PathBuf::from(home).join(".tpot"))
+        })
+        .ok_or_else(|| {
+            Error::Usage(format!(
+                "couldn't find your home directory, set {} to where teapot should keep its cache",
+                HOME_ENV
+            ))
+        })
+}
@@ -0,0 +1,403 @@
+use std::path::{Path, PathBuf};
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::{
+    cli::FeaturesData,
+    compiler::to_hex,
+    config::BASE_FEATURES,
+    error::{Error, IoContext, Result},
+    graph::{canonical_path, relative_path, LeafGraph},
+    workspace::Project,
+    write_file, Leaf, Source,
+};
+
+pub const LOCKFILE: &str = "tea.lock";
+const LOCKFILE_VERSION: u32 = 1;
+const HEADER: &str = "# This file is generated by teapot, it isn't meant to be edited by hand\n";
+
+/// How a command may treat tea.lock when resolution doesn't match it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockMode {
+    /// Rewrite it
+    #[default]
+    Update,
+    /// Fail instead of rewriting it
+    Locked,
+    /// Fail instead of rewriting it, and never fetch anything over the network
+    Frozen,
+}
+
+/// Every leaf the project resolved to, written to tea.lock in the project root
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lockfile {
+    version: u32,
+    #[serde(default, rename = "leaf")]
+    pub leaves: Vec<LockedLeaf>,
+}
+
+/// A single leaf with the features the last build used. Leaves that build didn't use are locked
+/// without features, so their versions stay put
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LockedLeaf {
+    pub name: String,
+    pub version: String,
+    /// Where the leaf came from, like `path+leaves/core`, `git+<url>?tag=v1.0#<commit>` or
+    /// `registry+<name>`
+    pub source: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    /// A hash of the leaf's tea.toml, sources and headers. Members of the project have none, as
+    /// they're expected to change with it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// The leaves this one depends on, by their id
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<String>,
+}
+
+impl LockedLeaf {
+    /// Names the leaf in the dependencies of other leaves, like `core 0.1.0 +fast`
+    pub fn id(&self) -> String {
+        let mut id = format!("{} {}", self.name, self.version);
+        self.features
+            .iter()
+            .for_each(|feature| id.push_str(&format!(" +{}", feature)));
+        id
+    }
+}
+
+impl Lockfile {
+    /// Locks every leaf in `built` with the features it's built with, then the leaves only `all`
+    /// reaches without any, so their versions stay put when a feature pulls them in later.
+    /// `members` are the leaves of the project itself
+    pub fn resolve(
+        built: &LeafGraph,
+        all: &LeafGraph,
+        root: &Path,
+        members: &[PathBuf],
+    ) -> Result<Self> {
+        let root = canonical_path(root);
+        let mut locked: Vec<LockedLeaf> = Vec::new();
+        // The leaves each entry of `locked` was added for, with the graph they're in
+        let mut added = Vec::new();
+        let mut indices = Vec::new();
+        for (graph, leaves) in [built, all].into_iter().enumerate() {
+            let mut graph_indices = Vec::new();
+            for (id, leaf) in leaves.leaves().iter().enumerate() {
+                let source = source(leaf, &root);
+                let package = &leaf.config.package;
+                let existing = locked.iter().position(|lock| {
+                    lock.name == package.name
+                        && lock.version == package.version.to_string()
+                        && lock.source == source
+                });
+                let index = match existing {
+                    Some(index) => index,
+                    None => {
+                        let mut lock = lock_leaf(leaf, &root, members)?;
+                        if graph > 0 {
+                            lock.features.clear();
+                        }
+                        locked.push(lock);
+                        added.push((graph, id));
+                        locked.len() - 1
+                    }
+                };
+                graph_indices.push(index);
+            }
+            indices.push(graph_indices);
+        }
+
+        let ids = locked.iter().map(LockedLeaf::id).collect::<Vec<String>>();
+        for (lock, (graph, id)) in locked.iter_mut().zip(added) {
+            let leaves = [built, all][graph];
+            lock.dependencies = leaves[id]
+                .dependencies
+                .iter()
+                .map(|dependency| ids[indices[graph][*dependency]].clone())
+                .collect();
+            lock.dependencies.sort();
+        }
+        locked.sort();
+
+        Ok(Self {
+            version: LOCKFILE_VERSION,
+            leaves: locked,
+        })
+    }
+
+    /// Reads tea.lock from the project root, if there is one
+    pub fn read(root: &Path) -> Result<Option<Self>> {
+        let path = root.join(LOCKFILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let text = std::fs::read_to_string(&path).with_path(&path)?;
+        let lockfile: Self = toml::from_str(&text)
+            .map_err(|error| Error::manifest(&path, error.message().trim_end()))?;
+        if lockfile.version != LOCKFILE_VERSION {
+            return Err(Error::manifest(
+                &path,
+                format!(
+                    "unsupported lockfile version {}, this teapot writes version {}",
+                    lockfile.version, LOCKFILE_VERSION
+                ),
+            ));
+        }
+
+        Ok(Some(lockfile))
+    }
+
+    pub fn write(&self, root: &Path) -> Result<()> {
+        let path = root.join(LOCKFILE);
+        let text = toml::to_string(self)
+            .map_err(|error| Error::manifest(&path, format!("couldn't write: {}", error)))?;
+        write_file(&path, format!("{}\n{}", HEADER, text))
+    }
+}
+
+/// Resolves every member of the project with `features` and brings tea.lock up to date with it.
+/// With `--locked` or `--frozen` a lockfile that would change is an error instead
+pub fn sync(project: &mut Project, mode: LockMode, features: &FeaturesData) -> Result<Lockfile> {
+    let members = project
+        .members
+        .iter()
+        .map(|member| canonical_path(member))
+        .collect::<Vec<PathBuf>>();
+    let existing = Lockfile::read(&project.root)?;
+    project.git.mode = mode;
+    project.git.pinned = existing
+        .iter()
+        .flat_map(|lockfile| &lockfile.leaves)
+        .filter_map(|leaf| leaf.source.rsplit_once('#'))
+        .map(|(source, commit)| (source.to_owned(), commit.to_owned()))
+        .collect();
+    project.registry.mode = mode;
+    project.registry.registries = project.registries();
+    project.registry.pinned = existing
+        .iter()
+        .flat_map(|lockfile| &lockfile.leaves)
+        .filter(|leaf| leaf.source.starts_with("registry+"))
+        .filter_map(|leaf| {
+            let version = Version::parse(&leaf.version).ok()?;
+            Some((format!("{} {}", leaf.source, leaf.name), version))
+        })
+        .collect();
+
+    // Dev-dependencies are locked too, so building tests doesn't change the lockfile. The graph
+    // with every feature is resolved first so the build picks the same versions, features that
+    // conflict are enabled together there, so its constraints aren't checked
+    let roots = project.members.clone();
+    let dev_dependencies = std::mem::replace(&mut project.dev_dependencies, true);
+    let check_constraints = std::mem::replace(&mut project.check_constraints, false);
+    let all_features = FeaturesData {
+        all_features: true,
+        ..FeaturesData::default()
+    };
+    let leaves = Leaf::load_graph(project, &roots, &all_features).and_then(|all| {
+        project.check_constraints = check_constraints;
+        Ok((Leaf::load_graph(project, &roots, features)?, all))
+    });
+    project.dev_dependencies = dev_dependencies;
+    project.check_constraints = check_constraints;
+    let (built, all) = leaves?;
+    let lockfile = Lockfile::resolve(&built, &all, &project.root, &members)?;
+
+    if existing.as_ref() == Some(&lockfile) {
+        return Ok(lockfile);
+    }
+
+    if mode != LockMode::Update {
+        let flag = if mode == LockMode::Frozen {
+            "--frozen"
+        } else {
+            "--locked"
+        };
+        let reason = match existing {
+            Some(existing) => changes(&existing, &lockfile),
+            None => format!("{} doesn't exist", LOCKFILE),
+        };
+        return Err(Error::Resolution(format!(
+            "{} needs to be updated but {} was passed\n{}",
+            LOCKFILE, flag, reason
+        )));
+    }
+
+    lockfile.write(&project.root)?;
+    Ok(lockfile)
+}
+
+/// Describes which leaves were added, removed or changed between two lockfiles
+fn changes(old: &Lockfile, new: &Lockfile) -> String {
+    let mut lines = Vec::new();
+    new.leaves
+        .iter()
+        .filter(|leaf| !old.leaves.contains(leaf))
+        .for_each(|leaf| {
+            let verb = if old.leaves.iter().any(|old| old.id() == leaf.id()) {
+                "changed"
+            } else {
+                "added"
+            };
+            lines.push(format!("  {} {} ({})", verb, leaf.id(), leaf.source));
+        });
+    old.leaves
+        .iter()
+        .filter(|leaf| !new.leaves.iter().any(|new| new.id() == leaf.id()))
+        .for_each(|leaf| lines.push(format!("  removed {} ({})", leaf.id(), leaf.source)));
+
+    lines.join("\n")
+}
+
+/// Where a leaf came from, like `path+leaves/core`, `git+<url>?tag=v1.0#<commit>` or
+/// `registry+<name>`. Paths are relative to `root`, which should be canonical
+pub fn source(leaf: &Leaf, root: &Path) -> String {
+    match &leaf.source {
+        Source::Path => match relative_path(&canonical_path(&leaf.path), root) {
+            source if source.as_os_str().is_empty() => "path+.".to_owned(),
+            source => format!("path+{}", source.display()),
+        },
+        Source::Git(checkout) => format!("{}#{}", checkout.source, checkout.commit),
+        Source::Registry(release) => format!("registry+{}", release.registry),
+    }
+}
+
+fn lock_leaf(leaf: &Leaf, root: &Path, members: &[PathBuf]) -> Result<LockedLeaf> {
+    let path = canonical_path(&leaf.path);
+    let source = source(leaf, root);
+    let checksum = if members.contains(&path) {
+        None
+    } else {
+        Some(checksum(&path)?)
+    };
+
+    // Platform features are left out so the lockfile is the same on every platform
+    let features = leaf
+        .features
+        .iter()
+        .filter(|feature| feature.enabled)
+        .filter(|feature| !BASE_FEATURES.contains(&feature.name.as_str()))
+        .map(|feature| feature.name.clone())
+        .collect();
+
+    Ok(LockedLeaf {
+        name: leaf.config.package.name.clone(),
+        version: leaf.config.package.version.to_string(),
+        source,
+        features,
+        checksum,
+        dependencies: Vec::new(),
+    })
+}
+
+/// Hashes the tea.toml, sources and headers of the leaf in `path`
+fn checksum(path: &Path) -> Result<String> {
+    let mut files = vec![path.join("tea.toml")];
+    for directory in ["src", "include"] {
+        WalkDir::new(path.join(directory))
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .for_each(|entry| files.push(entry.into_path()));
+    }
+    files.sort();
+
+    let mut hasher = Sha256::new();
+    for file in files {
+        let contents = std::fs::read(&file).with_path(&file)?;
+        let name = relative_path(&file, path);
+        hasher.update(name.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update((contents.len() as u64).to_le_bytes());
+        hasher.update(&contents);
+    }
+
+    Ok(to_hex(&hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::SelectionData;
+
+    fn write(path: &Path, contents: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    fn sync_with(app: &Path, mode: LockMode, features: &[&str]) -> Result<Lockfile> {
+        let selection = SelectionData {
+            packages: Vec::new(),
+            workspace: false,
+        };
+        let features = FeaturesData {
+            features: features.iter().map(ToString::to_string).collect(),
+            ..FeaturesData::default()
+        };
+        let mut project = Project::load_in(app, &selection)?;
+        sync(&mut project, mode, &features)
+    }
+
+    #[test]
+    fn fails_when_locked_and_the_lockfile_is_out_of_date() {
+        let root = std::env::temp_dir().join(format!("tpot-lock-{}", std::process::id()));
+        let app = root.join("app");
+        write(
+            &app.join("tea.toml"),
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\nfeatures = [\"fast\"]\n\n\
+             [dependencies]\ncore = { path = \"../core\" }\n",
+        );
+        write(&app.join("src/main.c"), "int main(void) { return 0; }\n");
+        write(
+            &root.join("core/tea.toml"),
+            "[package]\nname = \"core\"\nversion = \"0.1.0\"\n",
+        );
+        write(
+            &root.join("core/src/core.c"),
+            "int core(void) { return 1; }\n",
+        );
+
+        let error = sync_with(&app, LockMode::Locked, &[]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "tea.lock needs to be updated but --locked was passed\ntea.lock doesn't exist"
+        );
+        let lockfile = sync_with(&app, LockMode::Update, &[]).unwrap();
+        let ids = lockfile
+            .leaves
+            .iter()
+            .map(LockedLeaf::id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["app 0.1.0", "core 0.1.0"]);
+        assert_eq!(lockfile.leaves[0].dependencies, ["core 0.1.0"]);
+        assert_eq!(Lockfile::read(&app).unwrap(), Some(lockfile));
+        assert!(sync_with(&app, LockMode::Locked, &[]).is_ok());
+
+        // Picking other features changes what's locked
+        let error = sync_with(&app, LockMode::Frozen, &["fast"]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "tea.lock needs to be updated but --frozen was passed\n  \
+             added app 0.1.0 +fast (path+.)\n  removed app 0.1.0 (path+.)"
+        );
+
+        // So does a dependency changing underneath the project
+        write(
+            &root.join("core/src/core.c"),
+            "int core(void) { return 2; }\n",
+        );
+        let error = sync_with(&app, LockMode::Locked, &[]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "tea.lock needs to be updated but --locked was passed\n  \
+             changed core 0.1.0 (path+../core)"
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
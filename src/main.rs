@@ -1,21 +1,62 @@
 mod cli;
+mod compdb;
 mod compiler;
 mod config;
+mod diagnostic;
+mod error;
+mod features;
+mod git;
+mod graph;
+mod layout;
+mod lockfile;
+mod manifest;
+mod matrix;
+mod metadata;
+mod package;
+mod registry;
+mod scheduler;
+mod toolchain;
+mod tree;
+mod workspace;
 
 use clap::Parser;
-use cli::{AddData, BrewData, Cli, Commands, NewData};
+use cli::{
+    AddData, BrewData, Cli, Commands, CompdbData, FeaturesData, LintData, LockData, MatrixData,
+    MetadataData, MetadataFormat, NewData, PackageData, PublishData, RemoveData, SelectionData,
+    TreeData, TreeFormat,
+};
 use colored::Colorize;
-use compiler::{Compiler, OutputType};
-use config::TeaConfig;
-use std::path::{Path, PathBuf};
-use toml_edit::Document;
+use compiler::{Compiler, OutputType, StaleObject};
+use config::{BuildConfig, Manifests, Profile, TeaConfig, DEFAULT_FEATURE};
+use error::{Error, IoContext, Result};
+use features::{check_leaf_constraints, FeatureUnion, ResolvedFeatures};
+use git::{GitCheckout, GitReference, GitSource};
+use graph::{normalize_path, DependencyCycle, LeafGraph, LeafId, LeafKey};
+use layout::{Layout, DEFAULT_TARGET_DIR};
+use lockfile::LockMode;
+use registry::{RegistryRelease, DEFAULT_REGISTRY};
+use scheduler::{JobGraph, JobId};
+use semver::VersionReq;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use toml_edit::{Document, Item};
+use toolchain::Toolchain;
 use walkdir::WalkDir;
+use workspace::{Project, Workspace};
 
 use crate::config::BASE_FEATURES;
 
-fn new(cmd: NewData) {
+fn new(cmd: NewData) -> Result<()> {
     if cmd.lib == cmd.bin {
-        panic!("Only --lib or --bin can be set, and one must be set");
+        return Err(Error::Usage(
+            "Only --lib or --bin can be set, and one must be set".to_owned(),
+        ));
     }
 
     let mut config = Document::new();
@@ -25,35 +66,46 @@ fn new(cmd: NewData) {
 
     config["dependencies"] = toml_edit::table();
 
-    std::fs::create_dir_all(format!("{}", &cmd.name)).unwrap();
-    std::fs::write(format!("{}/tea.toml", &cmd.name), config.to_string()).unwrap();
-    std::fs::write(
-        format!("{}/.clang-format", &cmd.name),
+    let root = Path::new(&cmd.name);
+    create_dir(root)?;
+    write_file(&root.join("tea.toml"), config.to_string())?;
+    write_file(
+        &root.join(".clang-format"),
         include_bytes!("../assets/.clang-format"),
-    )
-    .unwrap();
+    )?;
 
-    std::fs::create_dir_all(format!("{}/src", &cmd.name)).unwrap();
+    create_dir(&root.join("src"))?;
 
     if cmd.lib {
-        std::fs::create_dir_all(format!("{}/include", &cmd.name)).unwrap();
-        std::fs::write(format!("{0}/include/{0}.h", &cmd.name), "#pragma once").unwrap();
-        std::fs::write(
-            format!("{0}/src/{0}.c", &cmd.name),
+        create_dir(&root.join("include"))?;
+        write_file(
+            &root.join("include").join(format!("{}.h", cmd.name)),
+            "#pragma once",
+        )?;
+        write_file(
+            &root.join("src").join(format!("{}.c", cmd.name)),
             format!("#include \"{}.h\"", cmd.name),
         )
-        .unwrap();
     } else {
-        std::fs::write(
-            format!("{}/src/main.c", &cmd.name),
+        write_file(
+            &root.join("src").join("main.c"),
             format!("#include <stdio.h>\n\nint {}_main() {{\n\tprintf(\"Hello, World!\");\n\treturn 0;\n}}", &cmd.name),
         )
-        .unwrap();
     }
 }
 
-fn load_config(path: &Path) -> TeaConfig {
-    TeaConfig::parse(path).expect("Can't find/parse tea.toml")
+fn create_dir(path: &Path) -> Result<()> {
+    std::fs::create_dir_all(path).with_path(path)
+}
+
+fn write_file<T: AsRef<[u8]>>(path: &Path, contents: T) -> Result<()> {
+    std::fs::write(path, contents).with_path(path)
+}
+
+/// Loads the selected leaves with `features`, and all of their dependencies
+fn load_leaves(project: &mut Project, features: &FeaturesData) -> Result<LeafGraph> {
+    let selected = project.selected.clone();
+    Leaf::load_graph(project, &selected, features)
 }
 
 #[derive(Debug, Clone)]
@@ -62,34 +114,180 @@ struct Feature {
     enabled: bool,
 }
 
-fn add_default_features(features: &[String]) -> Vec<String> {
+/// How many times the graph is loaded before giving up on unifying registry versions
+const MAX_RESOLVE_ATTEMPTS: usize = 10;
+
+/// Where a leaf's files came from
+#[derive(Debug, Clone)]
+enum Source {
+    Path,
+    Git(GitCheckout),
+    Registry(RegistryRelease),
+}
+
+/// Platform features are enabled on every leaf, whatever it's depended on with
+fn add_platform_feature(features: &[String]) -> Vec<String> {
     let mut features = features.to_owned();
     features.append(&mut vec![std::env::consts::OS.to_owned()]);
     features
 }
 
+/// The features a root of the graph is built with. Features asked for on the command line are
+/// enabled on every root that has them, along with the platform's
+fn root_features(config: &TeaConfig, selection: &FeaturesData) -> Vec<String> {
+    let features = config
+        .package
+        .features
+        .iter()
+        .filter(|feature| selection.all_features || selection.features.contains(feature))
+        .cloned()
+        .chain((!selection.no_default_features).then(|| DEFAULT_FEATURE.to_owned()))
+        .collect::<Vec<String>>();
+
+    // Platform features describe the target rather than a choice, so even
+    // --no-default-features keeps them
+    add_platform_feature(&features)
+}
+
+/// Fails if a feature asked for on the command line isn't a feature of any of the roots
+fn check_root_features(configs: &[TeaConfig], selection: &FeaturesData) -> Result<()> {
+    let known = configs
+        .iter()
+        .flat_map(|config| &config.package.features)
+        .map(String::as_str)
+        .collect::<Vec<&str>>();
+    let Some(unknown) = selection
+        .features
+        .iter()
+        .find(|feature| !known.contains(&feature.as_str()))
+    else {
+        return Ok(());
+    };
+
+    let mut message = match configs {
+        [config] => format!("{} has no feature named {}", config.package.name, unknown),
+        _ => format!(
+            "none of the selected leaves have a feature named {}",
+            unknown
+        ),
+    };
+    if let Some(suggestion) = diagnostic::suggest(unknown, known.iter().copied()) {
+        message.push_str(&format!(", did you mean {}?", suggestion));
+    }
+    Err(Error::Usage(message))
+}
+
 #[derive(Debug)]
-struct Leaf {
+pub struct Leaf {
     config: TeaConfig,
-    dependencies: Vec<Leaf>,
+    dependencies: Vec<LeafId>,
     features: Vec<Feature>,
     path: PathBuf,
+    source: Source,
     defines: Vec<(String, Option<String>)>,
     libraries: Vec<String>,
+    /// The leaf's name and a hash of its path and features, names its directory in the target
+    unit: String,
 }
 
 impl Leaf {
-    pub fn from_config(config: TeaConfig, enabled_features: Vec<String>, path: &Path) -> Self {
+    /// Loads the leaves at `roots` with `features`, and every leaf they depend on, into one
+    /// graph. Loading is repeated while the version picked for a registry leaf changes, so every leaf ends up
+    /// depending on the same release
+    pub fn load_graph(
+        project: &mut Project,
+        roots: &[PathBuf],
+        features: &FeaturesData,
+    ) -> Result<LeafGraph> {
+        let configs = roots
+            .iter()
+            .map(|path| project.manifests.config(path))
+            .collect::<Result<Vec<TeaConfig>>>()?;
+        check_root_features(&configs, features)?;
+        let features = configs
+            .iter()
+            .map(|config| root_features(config, features))
+            .collect::<Vec<Vec<String>>>();
+        // Features asked for while loading another graph, like the lockfile's, don't carry over
+        project.features = FeatureUnion::default();
+
+        for _ in 0..MAX_RESOLVE_ATTEMPTS {
+            project.registry.start();
+            project.features.start();
+            let leaves = Self::load_graph_once(project, roots, &features)?;
+            if !project.registry.changed() && !project.features.changed() {
+                // Checked once the graph settles, a leaf may be missing features before that
+                if project.check_constraints {
+                    leaves
+                        .leaves()
+                        .iter()
+                        .try_for_each(check_leaf_constraints)?;
+                }
+                return Ok(leaves);
+            }
+        }
+
+        Err(Error::Resolution(format!(
+            "couldn't settle on a version and the features of every leaf after {} attempts",
+            MAX_RESOLVE_ATTEMPTS
+        )))
+    }
+
+    fn load_graph_once(
+        project: &mut Project,
+        roots: &[PathBuf],
+        features: &[Vec<String>],
+    ) -> Result<LeafGraph> {
+        let mut leaves = LeafGraph::default();
+        for (path, features) in roots.iter().zip(features) {
+            let enabled_features = project.features.request(path, features);
+            // A root may already be in the graph as a dependency of an earlier root
+            let key = LeafKey::new(path, &enabled_features);
+            let root = match leaves.get(&key) {
+                Some(id) => id,
+                None => {
+                    let config = project.manifests.config(path)?;
+                    Self::from_config(
+                        &mut leaves,
+                        project,
+                        &mut Vec::new(),
+                        config,
+                        enabled_features,
+                        (path, Source::Path),
+                    )?
+                }
+            };
+            leaves.add_root(root);
+        }
+
+        Ok(leaves)
+    }
+
+    /// Adds a leaf and its dependencies to the graph, dependencies that are already in the graph
+    /// with the same features are reused rather than loaded again. `chain` holds the leaves
+    /// currently being loaded, so a leaf that depends on one of them is reported as a cycle
+    fn from_config(
+        leaves: &mut LeafGraph,
+        project: &mut Project,
+        chain: &mut Vec<(String, PathBuf)>,
+        config: TeaConfig,
+        enabled_features: Vec<String>,
+        (path, source): (&Path, Source),
+    ) -> Result<LeafId> {
+        let key = LeafKey::new(path, &enabled_features);
+        chain.push((config.package.name.clone(), key.path.clone()));
+
         let mut all_features = BASE_FEATURES
             .iter()
             .map(ToString::to_string)
             .collect::<Vec<String>>();
         all_features.append(&mut config.package.features.clone());
 
+        let resolved = ResolvedFeatures::resolve(&config, &enabled_features)?;
         let features: Vec<Feature> = all_features
             .iter()
             .map(|name| {
-                if enabled_features.contains(name) {
+                if resolved.enabled.contains(name) {
                     Feature {
                         name: name.clone(),
                         enabled: true,
@@ -103,31 +301,95 @@ impl Leaf {
             })
             .collect();
 
-        let mut dependencies = config.dependencies.base.clone();
-        for feature in &enabled_features {
-            if let Some(deps) = config.dependencies.features.get(feature) {
-                dependencies.append(&mut deps.clone());
-            }
+        let mut dependencies = config.dependencies.enabled(&resolved.enabled);
+        // Dev-dependencies are only for testing the roots, not the leaves they depend on
+        if project.dev_dependencies && chain.len() == 1 {
+            dependencies.append(&mut config.dev_dependencies.enabled(&resolved.enabled));
         }
+        dependencies.retain(|dependency| {
+            !dependency.optional || resolved.optional.contains(&dependency.name)
+        });
 
         let dependencies = dependencies
             .iter()
             .map(|dependency| {
-                let dep_config = load_config(
-                    &path.join(
-                        dependency
-                            .path
-                            .as_ref()
-                            .expect("Teapot only supports path based dependencies currently"),
-                    ),
-                );
-                Self::from_config(
-                    dep_config,
-                    add_default_features(&dependency.features),
-                    &path.join(dependency.path.as_ref().unwrap()),
-                )
+                let (dep_path, dep_source) =
+                    match (&dependency.path, &dependency.git, &dependency.registry) {
+                        (Some(dep_path), _, _) => {
+                            (normalize_path(&path.join(dep_path)), Source::Path)
+                        }
+                        (None, Some(git), _) => {
+                            let checkout = project.git.checkout(&dependency.name, git)?;
+                            (checkout.path.clone(), Source::Git(checkout))
+                        }
+                        (None, None, Some(registry)) => {
+                            let names = chain
+                                .iter()
+                                .map(|(name, _)| name.clone())
+                                .collect::<Vec<String>>();
+                            let release =
+                                project.registry.fetch(&dependency.name, registry, &names)?;
+                            (release.path.clone(), Source::Registry(release))
+                        }
+                        (None, None, None) => {
+                            return Err(Error::Resolution(format!(
+                                "{} depends on {} without a path, git repository or version",
+                                config.package.name, dependency.name
+                            )))
+                        }
+                    };
+                let mut dep_features = dependency.features.clone();
+                if let Some(features) = resolved.dependencies.get(&dependency.name) {
+                    dep_features.extend_from_slice(features);
+                }
+                if dependency.default_features {
+                    dep_features.push(DEFAULT_FEATURE.to_owned());
+                }
+                let dep_features = project
+                    .features
+                    .request(&dep_path, &add_platform_feature(&dep_features));
+                let dep_key = LeafKey::new(&dep_path, &dep_features);
+
+                if let Some(start) = chain.iter().position(|(_, path)| *path == dep_key.path) {
+                    let mut cycle = chain[start..].to_owned();
+                    cycle.push(chain[start].clone());
+                    return Err(DependencyCycle { chain: cycle }.into());
+                }
+
+                let location = match &dep_source {
+                    Source::Path | Source::Registry(_) => dep_path.display().to_string(),
+                    Source::Git(checkout) => {
+                        format!("{} at {}", checkout.source.url, &checkout.commit[..12])
+                    }
+                };
+                let id = match leaves.get(&dep_key) {
+                    Some(id) => id,
+                    None => {
+                        let dep_config = project.manifests.config(&dep_path)?;
+                        Self::from_config(
+                            leaves,
+                            project,
+                            chain,
+                            dep_config,
+                            dep_features,
+                            (&dep_path, dep_source),
+                        )?
+                    }
+                };
+
+                let version = &leaves[id].config.package.version;
+                match &dependency.version {
+                    Some(requirement) if !requirement.matches(version) => {
+                        Err(Error::Resolution(format!(
+                            "{} depends on {} {}, but {} is version {}",
+                            config.package.name, dependency.name, requirement, location, version
+                        )))
+                    }
+                    _ => Ok(id),
+                }
             })
-            .collect();
+            .collect::<Result<Vec<LeafId>>>()?;
+        chain.pop();
 
         let mut defines = config.defines.base.clone();
         features
@@ -149,50 +411,38 @@ impl Leaf {
                 }
             });
 
-        Leaf {
+        let unit = format!("{}-{}", config.package.name, key.hash());
+        let leaf = Leaf {
             config,
             dependencies,
             features,
             path: path.to_owned(),
+            source,
             defines,
             libraries,
-        }
-    }
+            unit,
+        };
 
-    pub fn get_dependencies(&self) -> Vec<String> {
-        let mut output = vec![self.config.package.name.clone()];
-        self.dependencies.iter().for_each(|dependency| {
-            output.append(&mut dependency.get_dependencies());
-        });
+        Ok(leaves.insert(key, leaf))
+    }
 
-        output
+    /// Binaries have a src/main.c defining `<name>_main`, every other leaf is a library
+    fn is_binary(&self) -> bool {
+        self.path.join("src").join("main.c").is_file()
     }
 
     fn clear() {
         print!("\r                                                      ");
     }
 
-    pub fn compile(&self, cmd: BrewData) {
-        self.dependencies
-            .iter()
-            .for_each(|dependency| dependency.compile(cmd.clone()));
-
-        let sources: Vec<PathBuf> = WalkDir::new(self.path.join("src"))
+    /// The leaf's sources, leaving out those for features that aren't enabled. A source is for a
+    /// feature when the last part of its stem names one, like `window.linux.c`
+    fn sources(&self) -> Vec<PathBuf> {
+        get_sources(&self.path.join("src"))
             .into_iter()
-            .filter_map(|e| e.ok())
-            .map(|entry| entry.path().to_owned())
             .filter(|path| {
-                path.extension().is_some() && path.extension().unwrap().to_str().unwrap() == "c"
-            })
-            .filter(|path| {
-                let feature_name = path
-                    .file_stem()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .split(".")
-                    .last()
-                    .unwrap();
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                let feature_name = stem.split('.').next_back().unwrap_or_default();
                 let feature = self
                     .features
                     .iter()
@@ -201,139 +451,642 @@ impl Leaf {
                                                               // feature specific or the source is
                                                               // enabled
             })
-            .collect();
+            .collect()
+    }
 
-        let mut compiler = Compiler::new(Path::new("target"));
-        compiler.include(&self.path.join("include"));
-        compiler.include(&self.path.join("src"));
+    /// The leaf's own headers and sources, then the public headers of each dependency
+    fn include_directories(&self, leaves: &LeafGraph) -> Vec<PathBuf> {
+        let mut directories = vec![self.path.join("include"), self.path.join("src")];
+        self.dependencies.iter().for_each(|dependency| {
+            directories.push(leaves[*dependency].path.join("include"));
+        });
+        directories
+    }
 
-        if cmd.release {
-            compiler.set_optimization_level(3);
-        }
-        if cmd.debug {
-            compiler.enable_debug_info()
-        }
+    /// A `FEATURE_<NAME>` for each enabled feature, then the leaf's own defines
+    fn all_defines(&self) -> Vec<(String, Option<String>)> {
+        self.features
+            .iter()
+            .filter(|feature| feature.enabled)
+            .map(|feature| (format!("FEATURE_{}", feature.name.to_uppercase()), None))
+            .chain(self.defines.iter().cloned())
+            .collect()
+    }
 
-        self.features.iter().for_each(|feature| {
-            if feature.enabled {
-                compiler
-                    .define::<String>(&format!("FEATURE_{}", feature.name.to_uppercase()), None);
-            }
+    /// The compiler for the leaf's sources, with its include directories and defines
+    fn compiler(&self, leaves: &LeafGraph, build: &Build) -> Result<Compiler> {
+        let mut compiler =
+            Compiler::new(&build.layout.leaf_directory(&self.unit), &build.toolchain);
+        compiler.use_profile(&build.profile)?;
+        self.include_directories(leaves)
+            .iter()
+            .for_each(|directory| compiler.include(directory));
+        self.all_defines().into_iter().for_each(|(name, value)| {
+            compiler.define(&name, value);
         });
 
-        self.dependencies.iter().for_each(|dependency| {
-            compiler.include(&dependency.path.join("include"));
+        Ok(compiler)
+    }
+
+    /// Adds jobs compiling and archiving this leaf to the graph, returning the archive job
+    fn plan(&self, leaves: &LeafGraph, build: &Build, graph: &mut JobGraph<Job>) -> Result<JobId> {
+        let sources = self.sources();
+        let mut compiler = self.compiler(leaves, build)?;
+        let stale = compiler.add_sources(&self.path, &sources);
+        let compiler = Arc::new(compiler);
+        let objects: Vec<JobId> = stale
+            .into_iter()
+            .map(|object| {
+                let job = Job::Compile {
+                    leaf: self.config.package.name.clone(),
+                    compiler: compiler.clone(),
+                    object,
+                };
+                graph.add(job, &[])
+            })
+            .collect();
+
+        let archive = Job::Archive {
+            leaf: self.config.package.name.clone(),
+            compiler,
+        };
+        Ok(graph.add(archive, &objects))
+    }
+
+    /// Adds jobs compiling the entry point of `root` and linking its binary, which waits on the
+    /// archive jobs of the leaves it links. `archives` holds the archive job of each leaf by its
+    /// id, and is empty when they were built beforehand
+    fn plan_link(
+        leaves: &LeafGraph,
+        root: LeafId,
+        build: &Build,
+        graph: &mut JobGraph<Job>,
+        archives: &[JobId],
+    ) -> Result<()> {
+        let leaf = &leaves[root];
+        let mut compiler = Compiler::new(build.layout.profile_directory(), &build.toolchain);
+        compiler.use_profile(&build.profile)?;
+
+        let stale = compiler.add_sources(
+            build.layout.profile_directory(),
+            &[build.layout.entry_point(&leaf.unit)],
+        );
+
+        let link_order = leaves.link_order(root);
+        link_order.iter().for_each(|id| {
+            let dependency = &leaves[*id];
+            compiler.add_static_library(
+                &build
+                    .layout
+                    .library(&dependency.unit, &dependency.config.package.name),
+            );
         });
 
-        self.defines.iter().for_each(|(name, value)| {
-            compiler.define(name, value.clone());
+        let mut libraries: Vec<&String> = Vec::new();
+        link_order
+            .iter()
+            .flat_map(|id| &leaves[*id].libraries)
+            .for_each(|library| {
+                if !libraries.contains(&library) {
+                    libraries.push(library);
+                }
+            });
+        libraries.iter().for_each(|library| {
+            compiler.add_system_library(library);
         });
 
-        compiler.compile(&sources, &self.config.package.name);
+        let compiler = Arc::new(compiler);
+        let mut inputs = link_order
+            .iter()
+            .filter_map(|id| archives.get(*id).copied())
+            .collect::<Vec<JobId>>();
+        stale.into_iter().for_each(|object| {
+            let job = Job::Compile {
+                leaf: leaf.config.package.name.clone(),
+                compiler: compiler.clone(),
+                object,
+            };
+            inputs.push(graph.add(job, &[]));
+        });
 
-        let progress = format!("[{0}/{0}]", sources.len())
-            .truecolor(0, 255, 0)
-            .bold();
-        Self::clear();
-        println!(
-            "\r{:13} {} {}",
-            progress,
-            "Linking".green().bold(),
-            &self.config.package.name
-        );
-        compiler.link(&self.config.package.name, OutputType::Library);
+        let link = Job::Link {
+            leaf: leaf.config.package.name.clone(),
+            compiler,
+        };
+        graph.add(link, &inputs);
+        Ok(())
     }
+}
+
+/// A unit of work in the build graph
+enum Job {
+    Compile {
+        leaf: String,
+        compiler: Arc<Compiler>,
+        object: StaleObject,
+    },
+    Archive {
+        leaf: String,
+        compiler: Arc<Compiler>,
+    },
+    Link {
+        leaf: String,
+        compiler: Arc<Compiler>,
+    },
+}
 
-    pub fn link(&self, cmd: BrewData) {
-        let mut compiler = Compiler::new(Path::new("target"));
-        if cmd.release {
-            compiler.set_optimization_level(3);
+impl Job {
+    fn run(&self, progress: &AtomicUsize, total: usize) -> Result<()> {
+        match self {
+            Job::Compile {
+                leaf,
+                compiler,
+                object,
+            } => {
+                compiler.compile_object(object)?;
+
+                let value = progress.fetch_add(1, Ordering::SeqCst) + 1;
+                let red = 1.0 - (value as f32 / total as f32);
+                let green = value as f32 / total as f32;
+                let progress_str = format!("[{}/{}]", value, total)
+                    .truecolor((red * 255.0) as u8, (green * 255.0) as u8, 0)
+                    .bold();
+                Leaf::clear();
+                print!(
+                    "\r{:13} {} {}",
+                    progress_str,
+                    "Compiling".green().bold(),
+                    leaf
+                );
+                let _ = std::io::stdout().flush();
+                Ok(())
+            }
+            Job::Archive { leaf, compiler } => {
+                Leaf::clear();
+                if compiler.is_up_to_date(leaf, OutputType::Library) {
+                    println!("\r{:13} {} {}", String::new(), "Fresh".green().bold(), leaf);
+                    return Ok(());
+                }
+
+                println!(
+                    "\r{:13} {} {}",
+                    String::new(),
+                    "Linking".green().bold(),
+                    leaf
+                );
+                compiler.link(leaf, OutputType::Library)
+            }
+            Job::Link { leaf, compiler } => {
+                Leaf::clear();
+                if compiler.is_up_to_date(leaf, OutputType::Binary) {
+                    print!("\r");
+                    return Ok(());
+                }
+
+                println!(
+                    "\r{:13} {} {}",
+                    String::new(),
+                    "Finishing".green().bold(),
+                    leaf
+                );
+                compiler.link(leaf, OutputType::Binary)
+            }
         }
-        if cmd.debug {
-            compiler.enable_debug_info()
+    }
+}
+
+/// Adds jobs compiling and archiving every leaf, returning the archive job of each leaf by its id
+fn plan_leaves(leaves: &LeafGraph, build: &Build, graph: &mut JobGraph<Job>) -> Result<Vec<JobId>> {
+    leaves
+        .leaves()
+        .iter()
+        .map(|leaf| leaf.plan(leaves, build, graph))
+        .collect()
+}
+
+/// Runs every job in the graph, failing with the errors of every job that failed
+fn run_jobs(graph: &JobGraph<Job>, build: &Build) -> Result<()> {
+    let total = graph
+        .jobs()
+        .iter()
+        .filter(|job| matches!(job, Job::Compile { .. }))
+        .count();
+    let progress = AtomicUsize::new(0);
+    let pool = scheduler::pool(build.jobs)?;
+
+    graph
+        .run(&pool, |job| job.run(&progress, total))
+        .map_err(|mut errors| {
+            Leaf::clear();
+            println!("\r");
+            if errors.len() == 1 {
+                errors.remove(0)
+            } else {
+                Error::Many(errors)
+            }
+        })
+}
+
+/// Only touches the file when its contents differ, so an unchanged entry point isn't recompiled
+fn write_if_changed(path: &Path, contents: &str) -> Result<()> {
+    if std::fs::read_to_string(path).is_ok_and(|existing| existing == contents) {
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        create_dir(parent)?;
+    }
+    write_file(path, contents)
+}
+
+/// Everything shared by every leaf in a build
+struct Build {
+    toolchain: Toolchain,
+    profile: Profile,
+    layout: Layout,
+    jobs: usize,
+}
+
+/// Resolves the toolchain, profile and target directory a build uses from the root tea.toml and
+/// the command line
+fn build_settings(cmd: &BrewData, project: &Project) -> Result<Build> {
+    let config = &project.build;
+    let toolchain = Toolchain::resolve(cmd.compiler.as_deref(), &config.toolchain);
+    let profile = Profile::resolve(cmd.profile_name(), &config.profiles).ok_or_else(|| {
+        Error::Usage(format!(
+            "No profile named {} in tea.toml, or it inherits from itself",
+            cmd.profile_name()
+        ))
+    })?;
+    let layout = Layout::resolve(cmd.target_dir.as_deref(), &project.root, &profile.name);
+
+    let jobs = cmd.jobs.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|jobs| jobs.get())
+            .unwrap_or(1)
+    });
+
+    Ok(Build {
+        toolchain,
+        profile,
+        layout,
+        jobs,
+    })
+}
+
+/// Brings tea.lock up to date, then builds the selected leaves
+fn sync_and_build(cmd: &BrewData, mut project: Project) -> Result<(LeafGraph, Build)> {
+    lockfile::sync(&mut project, cmd.lock.mode(), &cmd.features)?;
+    build_selected(cmd, &mut project)
+}
+
+/// Builds the selected leaves in one graph, linking the ones that are binaries. tea.lock has to
+/// be synced first
+fn build_selected(cmd: &BrewData, project: &mut Project) -> Result<(LeafGraph, Build)> {
+    let build = build_settings(cmd, project)?;
+    let leaves = load_leaves(project, &cmd.features)?;
+
+    compdb::write(&leaves, &build, &build.layout.compile_commands())?;
+
+    let mut graph = JobGraph::new();
+    let archives = plan_leaves(&leaves, &build, &mut graph)?;
+    for root in leaves.roots() {
+        let leaf = &leaves[*root];
+        if !leaf.is_binary() {
+            continue;
         }
 
-        compiler.compile(
-            &[Path::new("target/main.c").to_owned()],
-            &self.config.package.name,
+        let main = format!(
+            "void {0}_main();\nint main() {{\n\t{0}_main();\n}}",
+            leaf.config.package.name
         );
+        write_if_changed(&build.layout.entry_point(&leaf.unit), &main)?;
+        Leaf::plan_link(&leaves, *root, &build, &mut graph, &archives)?;
+    }
+    run_jobs(&graph, &build)?;
 
-        let dependencies = self.get_dependencies();
-        dependencies.iter().for_each(|dependency| {
-            compiler.add_static_library(dependency);
-        });
+    Ok((leaves, build))
+}
 
-        self.libraries.iter().for_each(|library| {
-            compiler.add_system_library(library);
+fn brew(cmd: BrewData) -> Result<()> {
+    let project = Project::load(&cmd.selection)?;
+    sync_and_build(&cmd, project).map(|_| ())
+}
+
+/// Runs a tool with inherited stdio, failing with a toolchain error if it can't be started
+fn run_tool(expression: duct::Expression, tool: &str) -> Result<std::process::Output> {
+    expression
+        .unchecked()
+        .run()
+        .map_err(|error| Error::toolchain(tool, format!("couldn't run: {}", error)))
+}
+
+fn pour(cmd: BrewData) -> Result<()> {
+    let project = Project::load(&cmd.selection)?;
+    if project.selected.len() != 1 {
+        return Err(Error::Usage(
+            "pour runs a single leaf, pick one with -p".to_owned(),
+        ));
+    }
+
+    let (leaves, build) = sync_and_build(&cmd, project)?;
+    let leaf = &leaves[leaves.roots()[0]];
+    if !leaf.is_binary() {
+        return Err(Error::Usage(format!(
+            "{} is a library, it has no src/main.c to run",
+            leaf.config.package.name
+        )));
+    }
+
+    let binary = build.layout.binary(&leaf.config.package.name);
+    let output = duct::cmd!(&binary).unchecked().run().with_path(&binary)?;
+    if !output.status.success() {
+        return Err(Error::Run {
+            program: binary.display().to_string(),
+            code: output.status.code(),
         });
+    }
 
-        Self::clear();
-        println!(
-            "\r{:13} {} {}",
-            String::new(),
-            "Finishing".green().bold(),
-            &self.config.package.name
-        );
-        compiler.link(&self.config.package.name, OutputType::Binary);
+    Ok(())
+}
+
+/// Reads the tea.toml in the current directory for `add` or `remove` to edit
+fn read_manifest() -> Result<(Document, TeaConfig)> {
+    let manifest = Path::new("tea.toml");
+    let text = std::fs::read_to_string(manifest)
+        .map_err(|_| Error::manifest(manifest, "no tea.toml in this directory to edit"))?;
+    let document = text
+        .parse::<Document>()
+        .map_err(|error| Error::manifest(manifest, error))?;
+    let config = Manifests::default().config(Path::new(""))?;
+
+    Ok((document, config))
+}
+
+/// Fails unless `feature` is one of the leaf's features
+fn check_feature(config: &TeaConfig, feature: &str) -> Result<()> {
+    let features = BASE_FEATURES
+        .iter()
+        .map(ToString::to_string)
+        .chain(config.package.features.iter().cloned())
+        .collect::<Vec<String>>();
+    if features.iter().any(|known| known == feature) {
+        return Ok(());
+    }
+
+    let mut message = format!("{} has no feature named {}", config.package.name, feature);
+    if let Some(suggestion) = diagnostic::suggest(feature, features.iter().map(String::as_str)) {
+        message.push_str(&format!(", did you mean {}?", suggestion));
     }
+    Err(Error::Usage(message))
 }
 
-fn brew(cmd: BrewData) {
-    let config = load_config(Path::new(""));
+/// The table of `[dependencies]` or `[dev-dependencies]` to edit, or its table for `feature`
+fn dependency_table<'a>(
+    document: &'a mut Document,
+    section: &str,
+    feature: Option<&str>,
+) -> Result<&'a mut toml_edit::Table> {
+    let manifest = Path::new("tea.toml");
+    let table = document
+        .entry(section)
+        .or_insert(toml_edit::table())
+        .as_table_mut()
+        .ok_or_else(|| Error::manifest(manifest, format!("{} must be a table", section)))?;
+    let Some(feature) = feature else {
+        return Ok(table);
+    };
 
-    let leaf = Leaf::from_config(config, add_default_features(&[]), Path::new(""));
-    leaf.compile(cmd.clone());
+    table
+        .entry(feature)
+        .or_insert(toml_edit::table())
+        .as_table_mut()
+        .ok_or_else(|| {
+            Error::manifest(manifest, format!("{}.{} must be a table", section, feature))
+        })
+}
 
-    let main = format!(
-        "void {0}_main();\nint main() {{\n\t{0}_main();\n}}",
-        leaf.config.package.name
-    );
-    std::fs::write("target/main.c", main).unwrap();
+/// Checks the leaf being added matches what `add` was asked for, returning its name
+fn check_added(cmd: &AddData, target: &TeaConfig, location: &str) -> Result<String> {
+    let name = &target.package.name;
+    if let Some(given) = cmd.name.as_ref().filter(|given| *given != name) {
+        return Err(Error::Usage(format!(
+            "the leaf at {} is called {}, not {}",
+            location, name, given
+        )));
+    }
+    if let Some(requirement) = &cmd.version {
+        let requirement = parse_requirement(requirement)?;
+        if !requirement.matches(&target.package.version) {
+            return Err(Error::Usage(format!(
+                "{} doesn't match the version of the leaf at {}, which is {}",
+                requirement, location, target.package.version
+            )));
+        }
+    }
+    for feature in &cmd.features {
+        check_feature(target, feature)?;
+    }
+
+    Ok(name.clone())
+}
 
-    leaf.link(cmd);
+fn parse_requirement(requirement: &str) -> Result<VersionReq> {
+    VersionReq::parse(requirement).map_err(|error| {
+        Error::Usage(format!(
+            "{} isn't a valid version requirement: {}",
+            requirement, error
+        ))
+    })
 }
 
-fn pour() {
-    let config = load_config(Path::new(""));
-    let leaf = Leaf::from_config(config, add_default_features(&[]), Path::new(""));
+fn add(cmd: AddData) -> Result<()> {
+    let (mut document, config) = read_manifest()?;
+    if let Some(feature) = &cmd.feature_gated {
+        check_feature(&config, feature)?;
+    }
+
+    let mut project = Project::load(&SelectionData {
+        packages: Vec::new(),
+        workspace: false,
+    })?;
+    let mut dependency = toml_edit::InlineTable::new();
+    let name = match (&cmd.path, &cmd.git) {
+        (Some(path), _) => {
+            let target = project.manifests.config(path)?;
+            dependency.insert("path", path.to_string_lossy().as_ref().into());
+            check_added(&cmd, &target, &path.display().to_string())?
+        }
+        (None, Some(url)) => {
+            let (key, reference) = match (&cmd.tag, &cmd.branch, &cmd.rev) {
+                (Some(tag), _, _) => ("tag", GitReference::Tag(tag.clone())),
+                (_, Some(branch), _) => ("branch", GitReference::Branch(branch.clone())),
+                (_, _, Some(rev)) => ("rev", GitReference::Rev(rev.clone())),
+                _ => ("", GitReference::Head),
+            };
+            let source = GitSource {
+                url: url.clone(),
+                reference,
+            };
+            // The name only keeps the repository apart in the cache until the leaf is read
+            let cache_name = cmd.name.clone().unwrap_or_else(|| {
+                let repository = url.trim_end_matches('/').rsplit('/').next();
+                let repository = repository.unwrap_or_default().trim_end_matches(".git");
+                repository.to_owned()
+            });
+            let checkout = project.git.checkout(&cache_name, &source)?;
+            let target = project.manifests.config(&checkout.path)?;
 
-    let brew = BrewData {
-        release: false,
-        debug: false,
+            dependency.insert("git", url.as_str().into());
+            if let Some(value) = cmd
+                .tag
+                .as_ref()
+                .or(cmd.branch.as_ref())
+                .or(cmd.rev.as_ref())
+            {
+                dependency.insert(key, value.as_str().into());
+            }
+            check_added(&cmd, &target, url)?
+        }
+        (None, None) => {
+            let (Some(name), Some(requirement)) = (&cmd.name, &cmd.version) else {
+                return Err(Error::Usage(
+                    "add needs a --path, a --git repository, or a name and --version to take \
+                     from a registry"
+                        .to_owned(),
+                ));
+            };
+            let requirement = parse_requirement(requirement)?;
+            project.registry.registries = project.registries();
+            let registry = project
+                .registry
+                .registry(cmd.registry.as_deref().unwrap_or(DEFAULT_REGISTRY))?;
+            let releases = registry.releases(name)?;
+            if !releases
+                .iter()
+                .any(|release| requirement.matches(&release.version))
+            {
+                return Err(Error::Resolution(format!(
+                    "registry {} has no release of {} matching {}",
+                    registry.name, name, requirement
+                )));
+            }
+            name.clone()
+        }
     };
-    leaf.compile(brew.clone());
+    if let Some(version) = &cmd.version {
+        dependency.insert("version", version.as_str().into());
+    }
+    if let Some(registry) = &cmd.registry {
+        dependency.insert("registry", registry.as_str().into());
+    }
+    if !cmd.features.is_empty() {
+        dependency.insert(
+            "features",
+            cmd.features.iter().collect::<toml_edit::Array>().into(),
+        );
+    }
 
-    let main = format!(
-        "void {0}_main();\nint main() {{\n\t{0}_main();\n}}",
-        leaf.config.package.name
+    let section = if cmd.dev {
+        "dev-dependencies"
+    } else {
+        "dependencies"
+    };
+    let table = dependency_table(&mut document, section, cmd.feature_gated.as_deref())?;
+    table[&name] = toml_edit::value(dependency);
+
+    let context = match &cmd.feature_gated {
+        Some(feature) => format!("{}.{}", section, feature),
+        None => section.to_owned(),
+    };
+    println!(
+        "\r{:13} {} {} to [{}]",
+        String::new(),
+        "Adding".green().bold(),
+        name,
+        context
     );
-    std::fs::write("target/main.c", main).unwrap();
+    write_file(Path::new("tea.toml"), document.to_string())
+}
 
-    leaf.link(brew);
+fn remove(cmd: RemoveData) -> Result<()> {
+    let (mut document, config) = read_manifest()?;
+    if let Some(feature) = &cmd.feature_gated {
+        check_feature(&config, feature)?;
+    }
+    let section = if cmd.dev {
+        "dev-dependencies"
+    } else {
+        "dependencies"
+    };
+    let context = match &cmd.feature_gated {
+        Some(feature) => format!("{}.{}", section, feature),
+        None => section.to_owned(),
+    };
 
-    duct::cmd!(format!("target/{}", leaf.config.package.name))
-        .run()
-        .unwrap();
-}
-
-fn add(cmd: AddData) {
-    let config_string = String::from_utf8(std::fs::read("tea.toml").unwrap_or_else(|_| {
-        println!("No tea.toml in this directory to add to");
-        std::process::exit(1);
-    }))
-    .unwrap();
-
-    let mut config = config_string.parse::<Document>().unwrap();
-    config["dependencies"][&cmd.name] = toml_edit::value(toml_edit::InlineTable::new());
-    config["dependencies"][&cmd.name]["path"] = toml_edit::value(cmd.path.to_str().unwrap());
-    config["dependencies"][&cmd.name]["features"] = toml_edit::value(
-        cmd.features
-            .unwrap_or_else(String::new)
-            .split(",")
-            .collect::<toml_edit::Array>(),
-    );
+    let table = document
+        .get_mut(section)
+        .and_then(|table| match &cmd.feature_gated {
+            Some(feature) => table.get_mut(feature),
+            None => Some(table),
+        })
+        .and_then(Item::as_table_like_mut);
+    // Without --feature-gated the tables of features aren't dependencies themselves
+    let names = table
+        .iter()
+        .flat_map(|table| table.iter())
+        .map(|(name, _)| name.to_owned())
+        .filter(|name| cmd.feature_gated.is_some() || check_feature(&config, name).is_err())
+        .collect::<Vec<String>>();
+    match table {
+        Some(table) if names.contains(&cmd.name) => {
+            table.remove(&cmd.name);
+            // A feature's table is only there for its dependencies, so it goes with the last one
+            if let Some(feature) = cmd.feature_gated.as_deref().filter(|_| table.is_empty()) {
+                document[section]
+                    .as_table_like_mut()
+                    .map(|dependencies| dependencies.remove(feature));
+            }
+        }
+        _ => {
+            let dependencies = if cmd.dev {
+                &config.dev_dependencies
+            } else {
+                &config.dependencies
+            };
+            let gated = dependencies
+                .features
+                .iter()
+                .find(|(_, dependencies)| {
+                    dependencies
+                        .iter()
+                        .any(|dependency| dependency.name == cmd.name)
+                })
+                .map(|(feature, _)| feature);
+
+            let mut message = format!("{} isn't in [{}]", cmd.name, context);
+            if let (Some(feature), None) = (gated, &cmd.feature_gated) {
+                message.push_str(&format!(
+                    ", it's only used with feature {}, remove it with --feature-gated {}",
+                    feature, feature
+                ));
+            } else if let Some(suggestion) =
+                diagnostic::suggest(&cmd.name, names.iter().map(String::as_str))
+            {
+                message.push_str(&format!(", did you mean {}?", suggestion));
+            }
+            return Err(Error::Usage(message));
+        }
+    }
 
-    std::fs::write("tea.toml", config.to_string()).unwrap();
+    println!(
+        "\r{:13} {} {} from [{}]",
+        String::new(),
+        "Removing".green().bold(),
+        cmd.name,
+        context
+    );
+    write_file(Path::new("tea.toml"), document.to_string())
 }
 
 fn get_sources(path: &Path) -> Vec<PathBuf> {
@@ -341,80 +1094,89 @@ fn get_sources(path: &Path) -> Vec<PathBuf> {
         .into_iter()
         .filter_map(|e| e.ok())
         .map(|e| e.path().to_owned())
-        .filter(|path| {
-            path.extension().is_some() && path.extension().unwrap().to_str().unwrap() == "c"
-        })
+        .filter(|path| path.extension().is_some_and(|extension| extension == "c"))
         .collect()
 }
 
-fn fmt() {
-    load_config(Path::new(""));
-    let sources = get_sources(Path::new("src"));
+fn fmt(cmd: SelectionData) -> Result<()> {
+    let project = Project::load(&cmd)?;
+    let sources = project
+        .selected
+        .iter()
+        .flat_map(|path| get_sources(&path.join("src")));
 
-    let mut args = vec!["-i"];
-    args.append(&mut sources.iter().map(|path| path.to_str().unwrap()).collect());
-    duct::cmd("clang-format", args).run().unwrap();
+    let mut args = vec!["-i".into()];
+    args.extend(sources.map(PathBuf::into_os_string));
+    let output = run_tool(duct::cmd("clang-format", args), "clang-format")?;
+    if !output.status.success() {
+        return Err(Error::toolchain("clang-format", "failed to format sources"));
+    }
 
     println!("Formatted");
+    Ok(())
 }
 
-fn lint() {
-    let config = load_config(Path::new(""));
-    let leaf = Leaf::from_config(config, add_default_features(&[]), Path::new(""));
-    let sources = get_sources(Path::new("src"));
+fn lint(cmd: LintData) -> Result<()> {
+    let mut project = Project::load(&cmd.selection)?;
+    lockfile::sync(&mut project, LockMode::Update, &cmd.features)?;
+    let leaves = load_leaves(&mut project, &cmd.features)?;
 
-    let mut args: Vec<String> = sources
-        .iter()
-        .map(|path| path.to_str().unwrap().to_owned())
-        .collect();
-    args.push("--".to_owned());
-    args.push("-Isrc".to_owned());
-    args.push("-Iinclude".to_owned());
-    leaf.dependencies.iter().for_each(|dependency| {
-        args.push(format!("-I{}", dependency.path.join("include").display()));
-    });
-    leaf.features
-        .iter()
-        .filter(|feature| feature.enabled)
-        .for_each(|feature| {
-            args.push(format!("-DFEATURE_{}", feature.name.to_uppercase()));
+    let mut failed = false;
+    for root in leaves.roots() {
+        let leaf = &leaves[*root];
+        let sources = get_sources(&leaf.path.join("src"));
+
+        let mut args: Vec<String> = sources
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+        args.push("--".to_owned());
+        leaf.include_directories(&leaves)
+            .iter()
+            .for_each(|directory| args.push(format!("-I{}", directory.display())));
+        leaf.all_defines().iter().for_each(|(name, value)| {
+            if let Some(v) = value {
+                args.push(format!("-D{}={}", name, v));
+            } else {
+                args.push(format!("-D{}", name));
+            }
         });
-    leaf.defines.iter().for_each(|(name, value)| {
-        if let Some(v) = value {
-            args.push(format!("-D{}={}", name, v));
-        } else {
-            args.push(format!("-D{}", name));
-        }
-    });
 
-    duct::cmd("clang-tidy", args).run().unwrap();
-}
+        let output = run_tool(duct::cmd("clang-tidy", args), "clang-tidy")?;
+        failed |= !output.status.success();
+    }
 
-fn sip() {
-    let config = load_config(Path::new(""));
-    let leaf = Leaf::from_config(config, add_default_features(&[]), Path::new(""));
-    let brew = BrewData {
-        release: false,
-        debug: false,
-    };
-    leaf.compile(BrewData {
-        release: false,
-        debug: false,
-    });
+    if failed {
+        return Err(Error::Lint);
+    }
 
-    let symbols = duct::cmd!(
+    Ok(())
+}
+
+/// Finds the `test_` functions in a leaf's archive and writes a runner calling each of them
+fn write_test_runner(leaf: &Leaf, build: &Build) -> Result<()> {
+    let output = run_tool(
+        duct::cmd!(
+            "nm",
+            "-f",
+            "just-symbols",
+            build.layout.library(&leaf.unit, &leaf.config.package.name)
+        )
+        .stdout_capture(),
         "nm",
-        "-f",
-        "just-symbols",
-        format!("target/lib{}.a", leaf.config.package.name)
-    )
-    .read()
-    .unwrap();
+    )?;
+    if !output.status.success() {
+        return Err(Error::toolchain(
+            "nm",
+            "couldn't list the symbols of the leaf",
+        ));
+    }
+    let symbols = String::from_utf8_lossy(&output.stdout);
     let tests = symbols
         .lines()
         .filter(|symbol| symbol.starts_with("test_"))
         .collect::<Vec<&str>>();
-    println!("Found tests: {:?}", tests);
+    println!("Found tests in {}: {:?}", leaf.config.package.name, tests);
 
     let forward = tests
         .iter()
@@ -430,24 +1192,372 @@ fn sip() {
         "#include <stdio.h>\n\n{}\n\nint main() {{\n{}\n}}",
         forward, body
     );
-    std::fs::write("target/main.c", test_runner).unwrap();
+    write_if_changed(&build.layout.entry_point(&leaf.unit), &test_runner)
+}
 
-    leaf.link(brew);
-    duct::cmd!(format!("./target/{}", leaf.config.package.name))
-        .run()
-        .unwrap();
+fn sip(cmd: BrewData) -> Result<()> {
+    let mut project = Project::load(&cmd.selection)?;
+    project.dev_dependencies = true;
+    lockfile::sync(&mut project, cmd.lock.mode(), &cmd.features)?;
+    run_tests(&cmd, &mut project)
+}
+
+/// Builds and runs the tests of the selected leaves. tea.lock has to be synced first
+fn run_tests(cmd: &BrewData, project: &mut Project) -> Result<()> {
+    let build = build_settings(cmd, project)?;
+    let leaves = load_leaves(project, &cmd.features)?;
+    compdb::write(&leaves, &build, &build.layout.compile_commands())?;
+
+    let mut graph = JobGraph::new();
+    plan_leaves(&leaves, &build, &mut graph)?;
+    run_jobs(&graph, &build)?;
+
+    let mut graph = JobGraph::new();
+    for root in leaves.roots() {
+        write_test_runner(&leaves[*root], &build)?;
+        Leaf::plan_link(&leaves, *root, &build, &mut graph, &[])?;
+    }
+    run_jobs(&graph, &build)?;
+
+    let mut failures = Vec::new();
+    for root in leaves.roots() {
+        let leaf = &leaves[*root];
+        let binary = build.layout.binary(&leaf.config.package.name);
+        let output = duct::cmd!(&binary).unchecked().run().with_path(&binary)?;
+        if !output.status.success() {
+            failures.push(Error::Test {
+                leaf: leaf.config.package.name.clone(),
+                status: output.status.to_string(),
+            });
+        }
+    }
+
+    match failures.len() {
+        0 => Ok(()),
+        1 => Err(failures.remove(0)),
+        _ => Err(Error::Many(failures)),
+    }
+}
+
+fn tree(cmd: TreeData) -> Result<()> {
+    let mut project = Project::load(&cmd.selection)?;
+    lockfile::sync(&mut project, cmd.lock.mode(), &cmd.features)?;
+    let leaves = load_leaves(&mut project, &cmd.features)?;
+
+    let (starts, edges) = if cmd.duplicates {
+        (tree::duplicates(&leaves), tree::Edges::dependents(&leaves))
+    } else if let Some(name) = &cmd.invert {
+        (
+            tree::named(&leaves, name)?,
+            tree::Edges::dependents(&leaves),
+        )
+    } else {
+        (
+            leaves.roots().to_owned(),
+            tree::Edges::dependencies(&leaves),
+        )
+    };
+
+    match cmd.format {
+        TreeFormat::Text => print!("{}", tree::text(&leaves, &project.root, &starts, &edges)),
+        TreeFormat::Dot => print!("{}", tree::dot(&leaves, &starts, &edges)),
+    }
+    Ok(())
+}
+
+fn metadata(cmd: MetadataData) -> Result<()> {
+    let mut project = Project::load(&cmd.build.selection)?;
+    let build = build_settings(&cmd.build, &project)?;
+    lockfile::sync(&mut project, cmd.build.lock.mode(), &cmd.build.features)?;
+    let leaves = load_leaves(&mut project, &cmd.build.features)?;
+
+    let metadata = metadata::Metadata::new(&leaves, &project.root, &build);
+    let output = match cmd.format {
+        MetadataFormat::Json => serde_json::to_string_pretty(&metadata),
+    }
+    .map_err(|error| Error::Usage(format!("couldn't write the metadata: {}", error)))?;
+    println!("{}", output);
+    Ok(())
+}
+
+/// Writes compile_commands.json for the graph without building it
+fn compdb(cmd: CompdbData) -> Result<()> {
+    let mut project = Project::load(&cmd.build.selection)?;
+    let build = build_settings(&cmd.build, &project)?;
+    lockfile::sync(&mut project, cmd.build.lock.mode(), &cmd.build.features)?;
+    let leaves = load_leaves(&mut project, &cmd.build.features)?;
+
+    let path = cmd
+        .output
+        .unwrap_or_else(|| project.root.join(compdb::COMPILE_COMMANDS));
+    compdb::write(&leaves, &build, &path)?;
+    println!(
+        "\r{:13} {} {}",
+        String::new(),
+        "Wrote".green().bold(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Builds, or with `--sip` tests, each selected leaf with every combination of features asked
+/// for, reporting every combination that fails. Dependencies that don't change between
+/// combinations are built once and reused
+fn feature_matrix(cmd: MatrixData) -> Result<()> {
+    let features = &cmd.build.features;
+    if features.all_features || features.no_default_features {
+        return Err(Error::Usage(
+            "matrix picks the features of each build, only --features can be added to all of them"
+                .to_owned(),
+        ));
+    }
+
+    let mut project = Project::load(&cmd.build.selection)?;
+    let selected = project.selected.clone();
+    let configs = selected
+        .iter()
+        .map(|path| project.manifests.config(path))
+        .collect::<Result<Vec<TeaConfig>>>()?;
+    check_root_features(&configs, features)?;
+    // Locked once for the features added to every combination, each build then keeps to it. The
+    // default features may break constraints a combination doesn't, so they're only checked then
+    project.dev_dependencies = cmd.sip;
+    project.check_constraints = false;
+    lockfile::sync(&mut project, cmd.build.lock.mode(), features)?;
+    project.check_constraints = true;
+
+    let mut failures = Vec::new();
+    let mut total = 0;
+    for (path, config) in selected.into_iter().zip(configs) {
+        let name = &config.package.name;
+        // Like other commands, each leaf only gets the features it has
+        let base = &features
+            .features
+            .iter()
+            .filter(|feature| config.package.features.contains(feature))
+            .cloned()
+            .collect::<Vec<String>>();
+        let combinations = if cmd.powerset {
+            matrix::powerset(&config, cmd.depth)
+        } else if cmd.each_feature {
+            matrix::each_feature(&config)
+        } else {
+            matrix::declared(&config).unwrap_or_else(|| matrix::each_feature(&config))
+        };
+
+        // Combinations that end up enabling the same features are only built once
+        let mut built: Vec<Vec<String>> = Vec::new();
+        for combination in combinations {
+            let description = combination.describe(name, base);
+            let enabled = match combination.resolve(&config, base) {
+                Ok(enabled) => enabled,
+                Err(error) => {
+                    println!(
+                        "\r{:13} {} {}: {}",
+                        String::new(),
+                        "Skipping".yellow().bold(),
+                        description,
+                        error
+                    );
+                    continue;
+                }
+            };
+            if built.contains(&enabled) {
+                continue;
+            }
+            built.push(enabled);
+
+            total += 1;
+            println!(
+                "\r{:13} {} {}",
+                String::new(),
+                "Checking".green().bold(),
+                description
+            );
+            let mut build = cmd.build.clone();
+            build.features = combination.features_data(base);
+            project.selected = vec![path.clone()];
+            let result = if cmd.sip {
+                run_tests(&build, &mut project)
+            } else {
+                build_selected(&build, &mut project).map(|_| ())
+            };
+            if let Err(error) = result {
+                failures.push((description, error));
+            }
+        }
+    }
+
+    println!(
+        "\r{:13} {} {} of {} combinations failed",
+        String::new(),
+        "Finished".green().bold(),
+        failures.len(),
+        total
+    );
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Matrix(failures))
+    }
+}
+
+/// The single selected leaf, which has to be a library to be packaged
+fn selected_library(project: &mut Project) -> Result<(PathBuf, TeaConfig)> {
+    let [path] = project.selected.as_slice() else {
+        return Err(Error::Usage(
+            "only a single leaf can be packaged at a time, pick one with -p".to_owned(),
+        ));
+    };
+    let path = path.clone();
+    let config = project.manifests.config(&path)?;
+    if path.join("src").join("main.c").is_file() {
+        return Err(Error::Usage(format!(
+            "{} is a binary, only libraries can be packaged",
+            config.package.name
+        )));
+    }
+
+    Ok((path, config))
+}
+
+/// Writes the archive of the leaf in `path` to target/package, then unless `--no-verify` unpacks
+/// it and builds it on its own, so a leaf that only builds in its workspace isn't shipped. Path
+/// dependencies are expected in `registry`, the one the leaf is published to
+fn package_leaf(
+    cmd: &PackageData,
+    project: &mut Project,
+    path: &Path,
+    config: &TeaConfig,
+    registry: &str,
+) -> Result<PathBuf> {
+    let manifest = path.join("tea.toml");
+    let workspace = Workspace::find(&mut project.manifests, path)?
+        .map(|workspace| project.manifests.document(&workspace.root).cloned())
+        .transpose()?;
+    let document = project.manifests.document(path)?;
+    let normalized = package::normalize_manifest(document, workspace.as_ref(), registry)
+        .map_err(|message| Error::manifest(&manifest, message))?;
+
+    let package = &config.package;
+    println!(
+        "\r{:13} {} {} v{}",
+        String::new(),
+        "Packaging".green().bold(),
+        package.name,
+        package.version
+    );
+    let build = build_settings(&cmd.build, project)?;
+    let directory = build.layout.package_directory();
+    let prefix = format!("{}-{}", package.name, package.version);
+    let archive = package::write_archive(path, &normalized, &prefix, &directory)?;
+
+    if !cmd.no_verify {
+        println!(
+            "\r{:13} {} {} v{}",
+            String::new(),
+            "Verifying".green().bold(),
+            package.name,
+            package.version
+        );
+        verify_package(&cmd.build, project, &archive, &directory.join(&prefix))?;
+    }
+
+    println!(
+        "\r{:13} {} {}",
+        String::new(),
+        "Packaged".green().bold(),
+        archive.display()
+    );
+    Ok(archive)
+}
+
+/// Unpacks `archive` into `directory` and builds it as a project of its own, with the build
+/// settings and registries of `project`
+fn verify_package(
+    cmd: &BrewData,
+    project: &Project,
+    archive: &Path,
+    directory: &Path,
+) -> Result<()> {
+    if directory.exists() {
+        std::fs::remove_dir_all(directory).with_path(directory)?;
+    }
+    let contents = std::fs::read(archive).with_path(archive)?;
+    registry::unpack_archive(archive, &contents, directory.parent().unwrap_or(directory))?;
+
+    let selection = SelectionData {
+        packages: Vec::new(),
+        workspace: false,
+    };
+    let mut packaged = Project::load_in(directory, &selection)?;
+    packaged.build = BuildConfig {
+        registries: project.registries(),
+        ..project.build.clone()
+    };
+
+    // The packaged leaf has no tea.lock of its own, so it's always resolved afresh
+    let mut cmd = cmd.clone();
+    cmd.target_dir = Some(directory.join(DEFAULT_TARGET_DIR));
+    cmd.selection = selection;
+    cmd.lock = LockData {
+        locked: false,
+        frozen: false,
+    };
+    sync_and_build(&cmd, packaged).map(|_| ())
+}
+
+fn package(cmd: PackageData) -> Result<()> {
+    let mut project = Project::load(&cmd.build.selection)?;
+    let (path, config) = selected_library(&mut project)?;
+    package_leaf(&cmd, &mut project, &path, &config, DEFAULT_REGISTRY).map(|_| ())
+}
+
+fn publish(cmd: PublishData) -> Result<()> {
+    let mut project = Project::load(&cmd.package.build.selection)?;
+    let (path, config) = selected_library(&mut project)?;
+    project.registry.registries = project.registries();
+    let registry = project
+        .registry
+        .registry(cmd.registry.as_deref().unwrap_or(DEFAULT_REGISTRY))?;
+    let package = &config.package;
+    // Checked before packaging too, so a version that can't be published isn't built first
+    registry.unpublished(&package.name, &package.version)?;
+
+    let archive = package_leaf(&cmd.package, &mut project, &path, &config, &registry.name)?;
+    println!(
+        "\r{:13} {} {} v{} ({})",
+        String::new(),
+        "Publishing".green().bold(),
+        package.name,
+        package.version,
+        registry.name
+    );
+    registry.publish(&package.name, &package.version, &archive)
 }
 
 fn main() {
     let cli = Cli::parse();
 
-    match cli.commands {
+    let result = match cli.commands {
         Commands::New(data) => new(data),
         Commands::Brew(data) => brew(data),
-        Commands::Pour => pour(),
+        Commands::Pour(data) => pour(data),
         Commands::Add(data) => add(data),
-        Commands::Format => fmt(),
-        Commands::Lint => lint(),
-        Commands::Sip => sip(),
+        Commands::Remove(data) => remove(data),
+        Commands::Format(data) => fmt(data),
+        Commands::Lint(data) => lint(data),
+        Commands::Sip(data) => sip(data),
+        Commands::Tree(data) => tree(data),
+        Commands::Metadata(data) => metadata(data),
+        Commands::Compdb(data) => compdb(data),
+        Commands::Matrix(data) => feature_matrix(data),
+        Commands::Package(data) => package(data),
+        Commands::Publish(data) => publish(data),
     };
+
+    if let Err(error) = result {
+        eprintln!("{} {}", "error:".red().bold(), error);
+        std::process::exit(error.exit_code());
+    }
 }
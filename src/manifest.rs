@@ -0,0 +1,892 @@
+use std::{
+    fmt::{self, Formatter},
+    ops::Range,
+    path::Path,
+};
+
+use semver::{Version, VersionReq};
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use toml::Spanned;
+
+use crate::{
+    config::{BASE_FEATURES, DEFAULT_FEATURE},
+    diagnostic::{self, Diagnostic, Severity},
+};
+
+/// A value in tea.toml. Only keys keep their position, tables that are only implied by a dotted
+/// header like `[profile.release]` have no position of their own
+#[derive(Debug)]
+pub enum Node {
+    String(String),
+    Integer,
+    Float,
+    Boolean(bool),
+    Array(Vec<Node>),
+    Table(Vec<Entry>),
+}
+
+/// A key and its value, in the order they appear in the file
+#[derive(Debug)]
+pub struct Entry {
+    pub key: String,
+    pub span: Range<usize>,
+    pub node: Node,
+}
+
+impl Node {
+    fn describe(&self) -> &'static str {
+        match self {
+            Self::String(_) => "a string",
+            Self::Integer => "an integer",
+            Self::Float => "a float",
+            Self::Boolean(_) => "a boolean",
+            Self::Array(_) => "an array",
+            Self::Table(_) => "a table",
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Node {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(NodeVisitor)
+    }
+}
+
+struct NodeVisitor;
+
+impl<'de> Visitor<'de> for NodeVisitor {
+    type Value = Node;
+
+    fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "a TOML value")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        Ok(Node::String(value.to_owned()))
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<Self::Value, E> {
+        Ok(Node::Integer)
+    }
+
+    fn visit_u64<E: de::Error>(self, _: u64) -> Result<Self::Value, E> {
+        Ok(Node::Integer)
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> Result<Self::Value, E> {
+        Ok(Node::Float)
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<Self::Value, E> {
+        Ok(Node::Boolean(value))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut nodes = Vec::new();
+        while let Some(node) = seq.next_element()? {
+            nodes.push(node);
+        }
+
+        Ok(Node::Array(nodes))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut entries = Vec::new();
+        // Keys are always strings in TOML, the only map with keys that aren't is a datetime
+        while let Some(key) = map
+            .next_key::<Spanned<String>>()
+            .map_err(|_| de::Error::custom("dates and times aren't supported in tea.toml"))?
+        {
+            entries.push(Entry {
+                span: key.span(),
+                key: key.into_inner(),
+                node: map.next_value()?,
+            });
+        }
+
+        Ok(Node::Table(entries))
+    }
+}
+
+const TOP_LEVEL_KEYS: &[&str] = &[
+    "package",
+    "features",
+    "constraints",
+    "matrix",
+    "dependencies",
+    "dev-dependencies",
+    "defines",
+    "libraries",
+    "toolchain",
+    "profile",
+    "workspace",
+    "registries",
+];
+const PACKAGE_KEYS: &[&str] = &["name", "version", "features"];
+const DEPENDENCY_KEYS: &[&str] = &[
+    "path",
+    "git",
+    "rev",
+    "tag",
+    "branch",
+    "version",
+    "registry",
+    "features",
+    "default-features",
+    "optional",
+    "workspace",
+];
+const CONSTRAINT_KEYS: &[&str] = &["conflicts", "one-of", "requires"];
+const MATRIX_KEYS: &[&str] = &["combinations"];
+const TOOLCHAIN_KEYS: &[&str] = &["compiler", "archiver"];
+const WORKSPACE_KEYS: &[&str] = &["members", "exclude", "package", "dependencies", "defines"];
+const WORKSPACE_PACKAGE_KEYS: &[&str] = &["version", "features"];
+const REGISTRY_KEYS: &[&str] = &["path"];
+const PROFILE_KEYS: &[&str] = &[
+    "inherits",
+    "opt-level",
+    "debug",
+    "cflags",
+    "ldflags",
+    "defines",
+    "sanitizers",
+];
+
+/// Checks a tea.toml against the manifest schema, returning every error and warning found
+pub fn validate(path: &Path, text: &str) -> Vec<Diagnostic> {
+    let mut validator = Validator {
+        path,
+        text,
+        features: BASE_FEATURES.iter().map(ToString::to_string).collect(),
+        features_known: true,
+        diagnostics: Vec::new(),
+    };
+
+    // toml_edit's `Document` drops where each key was once it's parsed, so the schema is checked
+    // against toml's serde view of the text, which keeps them. toml parses with toml_edit, so both
+    // accept and reject exactly the same files and report syntax errors at the same spot
+    match toml::from_str::<Node>(text) {
+        Ok(Node::Table(entries)) => validator.root(&entries),
+        Ok(_) => {
+            validator.error(None, "tea.toml must be a table");
+        }
+        Err(error) => {
+            validator.error(error.span(), error.message().trim_end());
+        }
+    }
+
+    validator.diagnostics
+}
+
+struct Validator<'a> {
+    path: &'a Path,
+    text: &'a str,
+    features: Vec<String>,
+    /// False when the features are inherited from the workspace, so they can't be checked here
+    features_known: bool,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Validator<'_> {
+    fn report<T: ToString>(
+        &mut self,
+        severity: Severity,
+        span: Option<Range<usize>>,
+        message: T,
+    ) -> &mut Diagnostic {
+        self.diagnostics.push(Diagnostic::new(
+            severity, self.path, self.text, span, message,
+        ));
+        self.diagnostics.last_mut().unwrap()
+    }
+
+    fn error<T: ToString>(&mut self, span: Option<Range<usize>>, message: T) -> &mut Diagnostic {
+        self.report(Severity::Error, span, message)
+    }
+
+    fn warning<T: ToString>(&mut self, span: Option<Range<usize>>, message: T) -> &mut Diagnostic {
+        self.report(Severity::Warning, span, message)
+    }
+
+    /// Reports a value that has the wrong type, pointing at its key
+    fn expected(&mut self, context: &str, expected: &str, entry: &Entry) {
+        self.error(
+            Some(entry.span.clone()),
+            format!(
+                "{} must be {}, found {}",
+                context,
+                expected,
+                entry.node.describe()
+            ),
+        );
+    }
+
+    /// Warns about keys of a table that teapot doesn't know about
+    fn unknown_key(&mut self, context: &str, entry: &Entry, known: &[&str]) {
+        let suggestion = diagnostic::suggest(&entry.key, known.iter().copied());
+        let warning = self.warning(
+            Some(entry.span.clone()),
+            format!(
+                "unknown key `{}` in {}, it will be ignored",
+                entry.key, context
+            ),
+        );
+        if let Some(suggestion) = suggestion {
+            warning.help = Some(format!("did you mean `{}`?", suggestion));
+        }
+    }
+
+    fn table<'n>(&mut self, context: &str, entry: &'n Entry) -> Option<&'n [Entry]> {
+        match &entry.node {
+            Node::Table(entries) => Some(entries),
+            _ => {
+                self.expected(context, "a table", entry);
+                None
+            }
+        }
+    }
+
+    fn string(&mut self, context: &str, entry: &Entry) {
+        if !matches!(entry.node, Node::String(_)) {
+            self.expected(context, "a string", entry);
+        }
+    }
+
+    /// Checks a semver version, like `1.2.3`
+    fn version(&mut self, context: &str, entry: &Entry) {
+        let Node::String(version) = &entry.node else {
+            self.expected(context, "a version, like \"1.2.3\"", entry);
+            return;
+        };
+        if let Err(error) = Version::parse(version) {
+            self.error(
+                Some(entry.span.clone()),
+                format!("{} isn't a semver version: {}", context, error),
+            )
+            .help = Some("versions have three parts, like \"1.2.3\"".to_owned());
+        }
+    }
+
+    /// Checks a semver version requirement, like `^1.2`
+    fn requirement(&mut self, context: &str, entry: &Entry) {
+        let Node::String(requirement) = &entry.node else {
+            self.expected(context, "a version requirement, like \"^1.2\"", entry);
+            return;
+        };
+        if let Err(error) = VersionReq::parse(requirement) {
+            self.error(
+                Some(entry.span.clone()),
+                format!("{} isn't a valid version requirement: {}", context, error),
+            );
+        }
+    }
+
+    fn boolean(&mut self, context: &str, entry: &Entry) {
+        if !matches!(entry.node, Node::Boolean(_)) {
+            self.expected(context, "a boolean", entry);
+        }
+    }
+
+    /// Checks an array of strings, returning the strings that are valid
+    fn string_array(&mut self, context: &str, entry: &Entry) -> Vec<String> {
+        let Node::Array(nodes) = &entry.node else {
+            self.expected(context, "an array of strings", entry);
+            return Vec::new();
+        };
+
+        nodes
+            .iter()
+            .filter_map(|node| match node {
+                Node::String(string) => Some(string.clone()),
+                _ => {
+                    self.error(
+                        Some(entry.span.clone()),
+                        format!(
+                            "every entry of {} must be a string, found {}",
+                            context,
+                            node.describe()
+                        ),
+                    );
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Checks for `{ workspace = true }`, returning true if the value is inherited. `allowed` are
+    /// the other keys that can be set alongside it
+    fn inherited(&mut self, context: &str, entry: &Entry, allowed: &[&str]) -> bool {
+        let Node::Table(fields) = &entry.node else {
+            return false;
+        };
+        let Some(workspace) = fields.iter().find(|field| field.key == "workspace") else {
+            return false;
+        };
+
+        if !matches!(workspace.node, Node::Boolean(true)) {
+            self.error(
+                Some(workspace.span.clone()),
+                format!(
+                    "{}.workspace can only be true, leave it out to set a value instead",
+                    context
+                ),
+            );
+        }
+        let mut known = vec!["workspace"];
+        known.extend_from_slice(allowed);
+        fields
+            .iter()
+            .filter(|field| !known.contains(&field.key.as_str()))
+            .for_each(|field| self.unknown_key(context, field, &known));
+
+        true
+    }
+
+    fn root(&mut self, entries: &[Entry]) {
+        // Features are declared in [package] but used by other tables, so they're found first.
+        // A workspace root doesn't need to be a leaf itself
+        match entries.iter().find(|entry| entry.key == "package") {
+            Some(package) => self.package(package),
+            None if entries.iter().any(|entry| entry.key == "workspace") => {}
+            None => {
+                self.error(None, "missing [package]");
+            }
+        }
+        let features = entries.iter().find(|entry| entry.key == "features");
+        if let Some(Node::Table(features)) = features.map(|entry| &entry.node) {
+            features
+                .iter()
+                .filter(|feature| feature.key != DEFAULT_FEATURE)
+                .for_each(|feature| self.features.push(feature.key.clone()));
+        }
+
+        for entry in entries {
+            match entry.key.as_str() {
+                "package" => {}
+                "features" => self.feature_table(entry),
+                "constraints" => self.constraints(entry),
+                "matrix" => self.matrix(entry),
+                "dependencies" | "dev-dependencies" => self.dependencies(entry),
+                "defines" => self.defines(entry),
+                "libraries" => self.libraries(entry),
+                "toolchain" => self.toolchain(entry),
+                "profile" => self.profiles(entry),
+                "workspace" => self.workspace(entry),
+                "registries" => self.registries(entry),
+                _ => self.unknown_key("tea.toml", entry, TOP_LEVEL_KEYS),
+            }
+        }
+    }
+
+    fn package(&mut self, package: &Entry) {
+        let Some(entries) = self.table("[package]", package) else {
+            return;
+        };
+
+        for entry in entries {
+            match entry.key.as_str() {
+                "name" => self.string("package.name", entry),
+                "version" => {
+                    if !self.inherited("package.version", entry, &[]) {
+                        self.version("package.version", entry);
+                    }
+                }
+                "features" => {
+                    if self.inherited("package.features", entry, &[]) {
+                        self.features_known = false;
+                    } else {
+                        let features = self.string_array("package.features", entry);
+                        self.features.extend(features);
+                    }
+                }
+                _ => self.unknown_key("[package]", entry, PACKAGE_KEYS),
+            }
+        }
+
+        ["name", "version"]
+            .iter()
+            .filter(|key| !entries.iter().any(|entry| entry.key == **key))
+            .for_each(|key| {
+                self.error(
+                    Some(package.span.clone()),
+                    format!("missing package.{}", key),
+                );
+            });
+    }
+
+    /// Checks `[features]`, where each feature lists the features it enables
+    fn feature_table(&mut self, features: &Entry) {
+        let Some(entries) = self.table("[features]", features) else {
+            return;
+        };
+
+        for entry in entries {
+            let context = format!("features.{}", entry.key);
+            for value in self.string_array(&context, entry) {
+                // Dependency features and optional dependencies are checked once dependencies
+                // are parsed
+                if !value.starts_with("dep:") && !value.contains('/') {
+                    self.feature(&format!("{} enables", context), &value, entry);
+                }
+            }
+        }
+    }
+
+    /// Reports `name` if it isn't a feature, `context` says where it's used
+    fn feature(&mut self, context: &str, name: &str, entry: &Entry) {
+        if self.is_feature(name) {
+            return;
+        }
+        let suggestion = diagnostic::suggest(name, self.features.iter().map(String::as_str))
+            .map(ToOwned::to_owned);
+        self.error(
+            Some(entry.span.clone()),
+            format!("{} `{}`, which isn't a feature", context, name),
+        )
+        .help = suggestion.map(|suggestion| format!("did you mean `{}`?", suggestion));
+    }
+
+    /// Checks `[matrix]`, the combinations of features `tpot matrix` builds
+    fn matrix(&mut self, matrix: &Entry) {
+        let Some(entries) = self.table("[matrix]", matrix) else {
+            return;
+        };
+
+        for entry in entries {
+            if entry.key != "combinations" {
+                self.unknown_key("[matrix]", entry, MATRIX_KEYS);
+                continue;
+            }
+            let Some(combinations) = string_arrays(&entry.node) else {
+                self.error(
+                    Some(entry.span.clone()),
+                    "matrix.combinations must be an array of sets of features, like \
+                     [[\"x11\"], [\"wayland\", \"default\"]]",
+                );
+                continue;
+            };
+            combinations
+                .iter()
+                .flatten()
+                .filter(|feature| *feature != DEFAULT_FEATURE)
+                .for_each(|feature| self.feature("matrix.combinations names", feature, entry));
+        }
+    }
+
+    /// Checks `[constraints]`, which limits the features that can be enabled together
+    fn constraints(&mut self, constraints: &Entry) {
+        let Some(entries) = self.table("[constraints]", constraints) else {
+            return;
+        };
+
+        for entry in entries {
+            match entry.key.as_str() {
+                "conflicts" | "one-of" => {
+                    let context = format!("constraints.{}", entry.key);
+                    let Some(groups) = string_arrays(&entry.node) else {
+                        self.error(
+                            Some(entry.span.clone()),
+                            format!(
+                                "{} must be an array of groups of features, like \
+                                 [[\"x11\", \"wayland\"]]",
+                                context
+                            ),
+                        );
+                        continue;
+                    };
+                    for feature in groups.iter().flatten() {
+                        self.feature(&format!("{} names", context), feature, entry);
+                    }
+                }
+                "requires" => {
+                    let Some(requires) = self.table("constraints.requires", entry) else {
+                        continue;
+                    };
+                    for required in requires {
+                        let context = format!("constraints.requires.{}", required.key);
+                        self.feature("constraints.requires names", &required.key, required);
+                        for requirement in self.string_array(&context, required) {
+                            requirement.split('|').for_each(|alternative| {
+                                self.feature(
+                                    &format!("{} requires", context),
+                                    alternative.trim(),
+                                    required,
+                                )
+                            });
+                        }
+                    }
+                }
+                _ => self.unknown_key("[constraints]", entry, CONSTRAINT_KEYS),
+            }
+        }
+    }
+
+    fn is_feature(&self, name: &str) -> bool {
+        !self.features_known || self.features.iter().any(|feature| feature == name)
+    }
+
+    /// Reports a feature table in `section` for a feature that was never declared
+    fn undeclared_feature(&mut self, section: &str, entry: &Entry) {
+        let suggestion = diagnostic::suggest(
+            &entry.key,
+            self.features.iter().map(String::as_str).collect::<Vec<_>>(),
+        )
+        .map(ToOwned::to_owned);
+        let error = self.error(
+            Some(entry.span.clone()),
+            format!(
+                "[{}.{}] is for feature `{}`, which isn't declared in package.features or \
+                 [features]",
+                section, entry.key, entry.key
+            ),
+        );
+        error.help = Some(match suggestion {
+            Some(suggestion) => format!("did you mean `{}`?", suggestion),
+            None => format!("add \"{}\" to package.features", entry.key),
+        });
+    }
+
+    /// Checks `[dependencies]` or `[dev-dependencies]`
+    fn dependencies(&mut self, dependencies: &Entry) {
+        let section = &dependencies.key;
+        let Some(entries) = self.table(&format!("[{}]", section), dependencies) else {
+            return;
+        };
+
+        for entry in entries {
+            if self.is_feature(&entry.key) && (self.features_known || !is_dependency(&entry.node)) {
+                let context = format!("[{}.{}]", section, entry.key);
+                let Some(dependencies) = self.table(&context, entry) else {
+                    continue;
+                };
+                dependencies
+                    .iter()
+                    .for_each(|dependency| self.dependency(dependency));
+            } else {
+                self.dependency(entry);
+            }
+        }
+    }
+
+    fn dependency(&mut self, entry: &Entry) {
+        let context = format!("dependency `{}`", entry.key);
+        let Node::Table(fields) = &entry.node else {
+            self.expected(&context, "a table, like { path = \"...\" }", entry);
+            return;
+        };
+
+        for field in fields {
+            match field.key.as_str() {
+                "path" | "git" | "rev" | "tag" | "branch" | "registry" => {
+                    self.string(&format!("{}.{}", entry.key, field.key), field)
+                }
+                "version" => self.requirement(&format!("{}.version", entry.key), field),
+                "workspace" | "optional" | "default-features" => {
+                    self.boolean(&format!("{}.{}", entry.key, field.key), field)
+                }
+                "features" => {
+                    self.string_array(&format!("{}.features", entry.key), field);
+                }
+                _ => self.unknown_key(&context, field, DEPENDENCY_KEYS),
+            }
+        }
+
+        let field = |key: &str| fields.iter().find(|field| field.key == key);
+        if let (Some(_), Some(git)) = (field("path"), field("git")) {
+            self.error(
+                Some(git.span.clone()),
+                format!("{} can't have both a path and a git repository", context),
+            );
+        }
+        // A registry next to a path is where the leaf comes from once it's published
+        if let (Some(registry), Some(_)) = (field("registry"), field("git")) {
+            self.error(
+                Some(registry.span.clone()),
+                format!(
+                    "{} can't come from a registry as well as a git repository",
+                    context
+                ),
+            );
+        }
+        let references = fields
+            .iter()
+            .filter(|field| matches!(field.key.as_str(), "rev" | "tag" | "branch"))
+            .collect::<Vec<&Entry>>();
+        if let Some(reference) = references.get(1) {
+            self.error(
+                Some(reference.span.clone()),
+                format!("{} can only set one of rev, tag and branch", context),
+            );
+        }
+        if let (Some(reference), None) = (references.first(), field("git")) {
+            self.error(
+                Some(reference.span.clone()),
+                format!(
+                    "{} sets a {} without a git repository",
+                    context, reference.key
+                ),
+            )
+            .help = Some("add git = \"<url>\"".to_owned());
+        }
+    }
+
+    fn define(&mut self, entry: &Entry) {
+        if self.inherited(&format!("define `{}`", entry.key), entry, &[]) {
+            return;
+        }
+        if !matches!(
+            entry.node,
+            Node::String(_) | Node::Integer | Node::Float | Node::Boolean(_)
+        ) {
+            self.expected(
+                &format!("define `{}`", entry.key),
+                "a string, integer, float or boolean",
+                entry,
+            );
+        }
+    }
+
+    fn defines(&mut self, defines: &Entry) {
+        let Some(entries) = self.table("[defines]", defines) else {
+            return;
+        };
+
+        for entry in entries {
+            match &entry.node {
+                Node::Table(fields) if fields.iter().any(|field| field.key == "workspace") => {
+                    self.define(entry)
+                }
+                Node::Table(defines) if self.is_feature(&entry.key) => {
+                    defines.iter().for_each(|define| self.define(define))
+                }
+                Node::Table(_) => self.undeclared_feature("defines", entry),
+                _ if self.is_feature(&entry.key) => {
+                    self.expected(&format!("[defines.{}]", entry.key), "a table", entry)
+                }
+                _ => self.define(entry),
+            }
+        }
+    }
+
+    fn libraries(&mut self, libraries: &Entry) {
+        let Some(entries) = self.table("[libraries]", libraries) else {
+            return;
+        };
+
+        for entry in entries {
+            match &entry.node {
+                Node::Table(_) if self.is_feature(&entry.key) => {}
+                Node::Table(_) => self.undeclared_feature("libraries", entry),
+                _ if self.is_feature(&entry.key) => {
+                    self.expected(&format!("[libraries.{}]", entry.key), "a table", entry)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn toolchain(&mut self, toolchain: &Entry) {
+        let Some(entries) = self.table("[toolchain]", toolchain) else {
+            return;
+        };
+
+        for entry in entries {
+            match entry.key.as_str() {
+                "compiler" | "archiver" => self.string(&format!("toolchain.{}", entry.key), entry),
+                _ => self.unknown_key("[toolchain]", entry, TOOLCHAIN_KEYS),
+            }
+        }
+    }
+
+    fn registries(&mut self, registries: &Entry) {
+        let Some(entries) = self.table("[registries]", registries) else {
+            return;
+        };
+
+        for entry in entries {
+            let context = format!("registry `{}`", entry.key);
+            let Node::Table(fields) = &entry.node else {
+                self.expected(&context, "a table, like { path = \"...\" }", entry);
+                continue;
+            };
+            if !fields.iter().any(|field| field.key == "path") {
+                self.error(Some(entry.span.clone()), format!("{} has no path", context));
+            }
+
+            for field in fields {
+                match field.key.as_str() {
+                    "path" => self.string(&format!("{}.path", entry.key), field),
+                    _ => self.unknown_key(&context, field, REGISTRY_KEYS),
+                }
+            }
+        }
+    }
+
+    fn workspace(&mut self, workspace: &Entry) {
+        let Some(entries) = self.table("[workspace]", workspace) else {
+            return;
+        };
+
+        for entry in entries {
+            match entry.key.as_str() {
+                "members" | "exclude" => {
+                    self.string_array(&format!("workspace.{}", entry.key), entry);
+                }
+                "package" => self.workspace_package(entry),
+                "dependencies" => {
+                    if let Some(dependencies) = self.table("[workspace.dependencies]", entry) {
+                        dependencies
+                            .iter()
+                            .for_each(|dependency| self.dependency(dependency));
+                    }
+                }
+                "defines" => {
+                    if let Some(defines) = self.table("[workspace.defines]", entry) {
+                        defines.iter().for_each(|define| self.define(define));
+                    }
+                }
+                _ => self.unknown_key("[workspace]", entry, WORKSPACE_KEYS),
+            }
+        }
+    }
+
+    fn workspace_package(&mut self, package: &Entry) {
+        let Some(entries) = self.table("[workspace.package]", package) else {
+            return;
+        };
+
+        for entry in entries {
+            match entry.key.as_str() {
+                "version" => self.version("workspace.package.version", entry),
+                "features" => {
+                    self.string_array("workspace.package.features", entry);
+                }
+                _ => self.unknown_key("[workspace.package]", entry, WORKSPACE_PACKAGE_KEYS),
+            }
+        }
+    }
+
+    fn profiles(&mut self, profiles: &Entry) {
+        let Some(entries) = self.table("[profile]", profiles) else {
+            return;
+        };
+
+        for entry in entries {
+            let context = format!("[profile.{}]", entry.key);
+            let Some(fields) = self.table(&context, entry) else {
+                continue;
+            };
+
+            for field in fields {
+                let key = format!("profile.{}.{}", entry.key, field.key);
+                match field.key.as_str() {
+                    "inherits" => self.string(&key, field),
+                    "opt-level" => {
+                        if !matches!(field.node, Node::Integer | Node::String(_)) {
+                            self.expected(&key, "an integer or string", field);
+                        }
+                    }
+                    "debug" => self.boolean(&key, field),
+                    "cflags" | "ldflags" | "sanitizers" => {
+                        self.string_array(&key, field);
+                    }
+                    "defines" => {
+                        if let Some(defines) = self.table(&key, field) {
+                            defines.iter().for_each(|define| self.define(define));
+                        }
+                    }
+                    _ => self.unknown_key(&context, field, PROFILE_KEYS),
+                }
+            }
+        }
+    }
+}
+
+/// An array of arrays of strings, like `[["x11"], ["wayland", "gl"]]`
+fn string_arrays(node: &Node) -> Option<Vec<Vec<String>>> {
+    let Node::Array(arrays) = node else {
+        return None;
+    };
+    arrays
+        .iter()
+        .map(|array| match array {
+            Node::Array(strings) => strings
+                .iter()
+                .map(|string| match string {
+                    Node::String(string) => Some(string.clone()),
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether a node looks like a single dependency rather than a table of them, which is how the
+/// two are told apart when the features are inherited and unknown
+fn is_dependency(node: &Node) -> bool {
+    match node {
+        Node::Table(fields) => fields.iter().any(|field| {
+            DEPENDENCY_KEYS.contains(&field.key.as_str()) && !matches!(field.node, Node::Table(_))
+        }),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate_str(text: &str) -> Vec<Diagnostic> {
+        validate(Path::new("tea.toml"), text)
+    }
+
+    #[test]
+    fn a_valid_manifest_has_no_diagnostics() {
+        let text = "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[dependencies]\n\
+                    core = { path = \"../core\", version = \"^0.1\" }\n";
+        assert!(validate_str(text).is_empty());
+    }
+
+    #[test]
+    fn unknown_keys_are_warnings_with_a_suggestion() {
+        let text = "[package]\nname = \"app\"\nversoin = \"0.1.0\"\nversion = \"0.1.0\"\n";
+        let diagnostics = validate_str(text);
+        assert_eq!(diagnostics.len(), 1);
+
+        let warning = &diagnostics[0];
+        assert_eq!(warning.severity, Severity::Warning);
+        assert_eq!(
+            warning.message,
+            "unknown key `versoin` in [package], it will be ignored"
+        );
+        assert_eq!(warning.help.as_deref(), Some("did you mean `version`?"));
+        let location = warning.location.as_ref().unwrap();
+        assert_eq!((location.line, location.column), (3, 1));
+    }
+
+    #[test]
+    fn bad_versions_are_errors() {
+        let text = "[package]\nname = \"app\"\nversion = \"1.2\"\n\n[dependencies]\n\
+                    core = { version = \"one\" }\n";
+        let diagnostics = validate_str(text);
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics
+            .iter()
+            .all(|diagnostic| diagnostic.severity == Severity::Error));
+
+        assert!(diagnostics[0]
+            .message
+            .starts_with("package.version isn't a semver version"));
+        assert_eq!(
+            diagnostics[0].help.as_deref(),
+            Some("versions have three parts, like \"1.2.3\"")
+        );
+        assert_eq!(diagnostics[0].location.as_ref().unwrap().line, 3);
+
+        assert!(diagnostics[1]
+            .message
+            .starts_with("core.version isn't a valid version requirement"));
+        assert_eq!(diagnostics[1].location.as_ref().unwrap().line, 6);
+    }
+}
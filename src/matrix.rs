@@ -0,0 +1,184 @@
+use crate::{
+    add_platform_feature,
+    cli::FeaturesData,
+    config::{TeaConfig, DEFAULT_FEATURE},
+    error::Result,
+    features::{check_constraints, ResolvedFeatures},
+};
+
+/// A set of features `tpot matrix` builds a leaf with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Combination {
+    pub features: Vec<String>,
+    pub default_features: bool,
+}
+
+impl Combination {
+    fn new(features: &[String]) -> Self {
+        Self {
+            features: features
+                .iter()
+                .filter(|feature| *feature != DEFAULT_FEATURE)
+                .cloned()
+                .collect(),
+            default_features: features.iter().any(|feature| feature == DEFAULT_FEATURE),
+        }
+    }
+
+    /// The features to build with, `base` holds the features added to every combination
+    pub fn features_data(&self, base: &[String]) -> FeaturesData {
+        let mut features = base.to_owned();
+        self.features
+            .iter()
+            .filter(|feature| !base.contains(feature))
+            .for_each(|feature| features.push(feature.clone()));
+
+        FeaturesData {
+            features,
+            all_features: false,
+            no_default_features: !self.default_features,
+        }
+    }
+
+    /// The flags that build the leaf with this combination, like
+    /// `-p app --no-default-features --features x11,gl`
+    pub fn describe(&self, leaf: &str, base: &[String]) -> String {
+        let features = self.features_data(base);
+        let mut description = format!("-p {}", leaf);
+        if features.no_default_features {
+            description.push_str(" --no-default-features");
+        }
+        if !features.features.is_empty() {
+            description.push_str(&format!(" --features {}", features.features.join(",")));
+        }
+        description
+    }
+
+    /// Everything the combination enables in the leaf, failing if it breaks the leaf's
+    /// constraints
+    pub fn resolve(&self, config: &TeaConfig, base: &[String]) -> Result<Vec<String>> {
+        let mut requested = self.features_data(base).features;
+        if self.default_features {
+            requested.push(DEFAULT_FEATURE.to_owned());
+        }
+
+        let mut enabled =
+            ResolvedFeatures::resolve(config, &add_platform_feature(&requested))?.enabled;
+        check_constraints(config, &enabled)?;
+        enabled.sort();
+        Ok(enabled)
+    }
+}
+
+/// The default features, no features, then each feature on its own
+pub fn each_feature(config: &TeaConfig) -> Vec<Combination> {
+    let mut combinations = vec![
+        Combination::new(&[DEFAULT_FEATURE.to_owned()]),
+        Combination::new(&[]),
+    ];
+    config
+        .package
+        .features
+        .iter()
+        .for_each(|feature| combinations.push(Combination::new(std::slice::from_ref(feature))));
+    combinations
+}
+
+/// Every combination of up to `depth` features, smallest first
+pub fn powerset(config: &TeaConfig, depth: Option<usize>) -> Vec<Combination> {
+    let features = &config.package.features;
+    let depth = depth.unwrap_or(features.len()).min(features.len());
+
+    let mut sets: Vec<Vec<String>> = vec![Vec::new()];
+    let mut combinations = vec![Combination::new(&[])];
+    for _ in 0..depth {
+        // Each set grows by the features declared after its last one, so no set repeats
+        sets = sets
+            .iter()
+            .flat_map(|set| {
+                let start = set
+                    .last()
+                    .and_then(|last| features.iter().position(|feature| feature == last))
+                    .map_or(0, |index| index + 1);
+                features[start..].iter().map(move |feature| {
+                    let mut set = set.clone();
+                    set.push(feature.clone());
+                    set
+                })
+            })
+            .collect();
+        combinations.extend(sets.iter().map(|set| Combination::new(set)));
+    }
+
+    combinations
+}
+
+/// The combinations listed in the leaf's `[matrix]`
+pub fn declared(config: &TeaConfig) -> Option<Vec<Combination>> {
+    config.matrix.as_ref().map(|combinations| {
+        combinations
+            .iter()
+            .map(|features| Combination::new(features))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(features: &[&str]) -> TeaConfig {
+        let manifest = format!(
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\nfeatures = {:?}\n",
+            features
+        );
+        TeaConfig::parse(&manifest.parse().unwrap(), None).unwrap()
+    }
+
+    fn sets(combinations: &[Combination]) -> Vec<Vec<&str>> {
+        combinations
+            .iter()
+            .map(|combination| {
+                assert!(!combination.default_features);
+                combination.features.iter().map(String::as_str).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn powerset_of_every_feature() {
+        let combinations = powerset(&config(&["a", "b", "c"]), None);
+        assert_eq!(
+            sets(&combinations),
+            vec![
+                vec![],
+                vec!["a"],
+                vec!["b"],
+                vec!["c"],
+                vec!["a", "b"],
+                vec!["a", "c"],
+                vec!["b", "c"],
+                vec!["a", "b", "c"],
+            ]
+        );
+    }
+
+    #[test]
+    fn powerset_up_to_a_depth() {
+        let config = config(&["a", "b", "c"]);
+        assert_eq!(
+            sets(&powerset(&config, Some(1))),
+            vec![vec![], vec!["a"], vec!["b"], vec!["c"]]
+        );
+        assert_eq!(sets(&powerset(&config, Some(0))), vec![Vec::<&str>::new()]);
+        assert_eq!(powerset(&config, Some(5)), powerset(&config, None));
+    }
+
+    #[test]
+    fn powerset_without_features() {
+        assert_eq!(
+            sets(&powerset(&config(&[]), None)),
+            vec![Vec::<&str>::new()]
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::{
+    graph::{canonical_path, normalize_path, LeafGraph, LeafId},
+    lockfile, Build,
+};
+
+/// Bumped whenever a field is removed or changes meaning, adding fields doesn't bump it
+pub const METADATA_VERSION: u32 = 1;
+
+/// The resolved graph as `tpot metadata` prints it, for editors and scripts. Every path is
+/// absolute
+#[derive(Debug, Serialize)]
+pub struct Metadata {
+    version: u32,
+    /// The workspace root, or the leaf's directory outside of a workspace
+    root: PathBuf,
+    profile: String,
+    target_directory: PathBuf,
+    /// The ids of the selected leaves
+    roots: Vec<String>,
+    leaves: Vec<LeafMetadata>,
+}
+
+#[derive(Debug, Serialize)]
+struct LeafMetadata {
+    /// Unique for each build of a leaf, the same leaf built with other features has another id
+    id: String,
+    name: String,
+    version: String,
+    /// `binary` or `library`
+    kind: &'static str,
+    path: PathBuf,
+    manifest_path: PathBuf,
+    /// Where the leaf came from, as written in tea.lock
+    source: String,
+    /// Every feature of the leaf and whether it's enabled
+    features: BTreeMap<String, bool>,
+    /// What the leaf's sources are compiled with, in order, from the profile, features and
+    /// `[defines]`
+    defines: Vec<Define>,
+    /// System libraries linked into binaries depending on the leaf
+    libraries: Vec<String>,
+    include_directories: Vec<PathBuf>,
+    /// The sources compiled, without those for features that aren't enabled
+    sources: Vec<PathBuf>,
+    /// The ids of the leaves this one depends on
+    dependencies: Vec<String>,
+    artifacts: Artifacts,
+}
+
+#[derive(Debug, Serialize)]
+struct Define {
+    name: String,
+    value: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Artifacts {
+    library: PathBuf,
+    /// Only binaries are linked, and only when they're selected
+    binary: Option<PathBuf>,
+}
+
+impl Metadata {
+    pub fn new(leaves: &LeafGraph, root: &Path, build: &Build) -> Self {
+        let root = canonical_path(root);
+        Self {
+            version: METADATA_VERSION,
+            profile: build.profile.name.clone(),
+            target_directory: absolute(build.layout.target_directory()),
+            roots: leaves
+                .roots()
+                .iter()
+                .map(|root| leaves[*root].unit.clone())
+                .collect(),
+            leaves: (0..leaves.leaves().len())
+                .map(|id| LeafMetadata::new(id, leaves, &root, build))
+                .collect(),
+            root,
+        }
+    }
+}
+
+impl LeafMetadata {
+    fn new(id: LeafId, leaves: &LeafGraph, root: &Path, build: &Build) -> Self {
+        let leaf = &leaves[id];
+        let name = &leaf.config.package.name;
+        let linked = leaf.is_binary() && leaves.roots().contains(&id);
+        let defines = build
+            .profile
+            .defines
+            .iter()
+            .cloned()
+            .chain(leaf.all_defines())
+            .map(|(name, value)| Define { name, value })
+            .collect();
+
+        Self {
+            id: leaf.unit.clone(),
+            name: name.clone(),
+            version: leaf.config.package.version.to_string(),
+            kind: if leaf.is_binary() {
+                "binary"
+            } else {
+                "library"
+            },
+            path: absolute(&leaf.path),
+            manifest_path: absolute(&leaf.path.join("tea.toml")),
+            source: lockfile::source(leaf, root),
+            features: leaf
+                .features
+                .iter()
+                .map(|feature| (feature.name.clone(), feature.enabled))
+                .collect(),
+            defines,
+            libraries: leaf.libraries.clone(),
+            include_directories: leaf
+                .include_directories(leaves)
+                .iter()
+                .map(|directory| absolute(directory))
+                .collect(),
+            sources: leaf.sources().iter().map(|path| absolute(path)).collect(),
+            dependencies: leaf
+                .dependencies
+                .iter()
+                .map(|dependency| leaves[*dependency].unit.clone())
+                .collect(),
+            artifacts: Artifacts {
+                library: absolute(&build.layout.library(&leaf.unit, name)),
+                binary: linked.then(|| absolute(&build.layout.binary(name))),
+            },
+        }
+    }
+}
+
+/// `path` made absolute without touching the filesystem, since artifacts may not exist yet
+fn absolute(path: &Path) -> PathBuf {
+    let current = std::env::current_dir().unwrap_or_default();
+    normalize_path(&current.join(path))
+}
@@ -0,0 +1,391 @@
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use toml_edit::{Decor, Document, InlineTable, Item, KeyMut, Table, Value};
+use walkdir::WalkDir;
+
+use crate::{
+    config::{is_inherited, BASE_FEATURES, DEFAULT_FEATURE},
+    error::{IoContext, Result},
+    registry::DEFAULT_REGISTRY,
+};
+
+/// Files at the top of a leaf packaged along with it, matched by the start of their name
+const LICENSE_FILES: &[&str] = &["LICENSE", "LICENCE", "COPYING", "NOTICE", "UNLICENSE"];
+
+/// Rewrites a leaf's tea.toml so it stands on its own once packaged. Anything inherited is
+/// copied from `workspace`, the tea.toml of the workspace root, and path dependencies come from
+/// `registry`, the one the leaf is published to, instead, so they need a version
+pub fn normalize_manifest(
+    document: &Document,
+    workspace: Option<&Document>,
+    registry: &str,
+) -> std::result::Result<Document, String> {
+    let mut document = document.clone();
+    document.remove("workspace");
+    // Registry paths are only meaningful on this machine, the project using the leaf has its own
+    document.remove("registries");
+    // Only the leaves being tested get their dev-dependencies, which a packaged leaf never is
+    document.remove("dev-dependencies");
+
+    let mut features = BASE_FEATURES
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>();
+    if let Some(package) = document.get_mut("package").and_then(Item::as_table_mut) {
+        for key in ["version", "features"] {
+            if package.get(key).is_some_and(is_inherited) {
+                package[key] = inherited(workspace, "package", key)?;
+                // A dotted key like `version.workspace = true` has no space before the `=`
+                package.key_decor_mut(key).map(Decor::clear);
+            }
+        }
+        package
+            .get("features")
+            .and_then(Item::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .for_each(|feature| features.push(feature.to_owned()));
+    }
+    if let Some(table) = document.get("features").and_then(Item::as_table) {
+        table
+            .iter()
+            .filter(|(name, _)| *name != DEFAULT_FEATURE)
+            .for_each(|(name, _)| features.push(name.to_owned()));
+    }
+
+    if let Some(dependencies) = document
+        .get_mut("dependencies")
+        .and_then(Item::as_table_mut)
+    {
+        for_each_entry(dependencies, &features, |name, item| {
+            normalize_dependency(name, item, workspace, registry)
+        })?;
+    }
+    if let Some(defines) = document.get_mut("defines").and_then(Item::as_table_mut) {
+        for_each_entry(defines, &features, |name, item| {
+            let inherits = is_inherited(item);
+            if inherits {
+                *item = inherited(workspace, "defines", name)?;
+            }
+            Ok(inherits)
+        })?;
+    }
+
+    Ok(document)
+}
+
+/// Calls `normalize` with every entry of a table like `[dependencies]`, including the entries
+/// in the table of each feature. `normalize` returns whether it replaced the entry
+fn for_each_entry(
+    table: &mut Table,
+    features: &[String],
+    mut normalize: impl FnMut(&str, &mut Item) -> std::result::Result<bool, String>,
+) -> std::result::Result<(), String> {
+    let mut normalize = |mut name: KeyMut, item: &mut Item| {
+        if normalize(&name, item)? {
+            name.fmt();
+        }
+        Ok::<(), String>(())
+    };
+    for (name, item) in table.iter_mut() {
+        let is_feature = features.contains(&name.to_string());
+        match item.as_table_like_mut() {
+            Some(feature) if is_feature => {
+                for (name, item) in feature.iter_mut() {
+                    normalize(name, item)?;
+                }
+            }
+            _ => normalize(name, item)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces an inherited dependency with the workspace's, and a path dependency with the version
+/// it's published as in `registry`
+fn normalize_dependency(
+    name: &str,
+    item: &mut Item,
+    workspace: Option<&Document>,
+    registry: &str,
+) -> std::result::Result<bool, String> {
+    let own = inline_table(item).ok_or_else(|| format!("dependency {} must be a table", name))?;
+    let inherits = is_inherited(item);
+    if !inherits && !own.contains_key("path") {
+        return Ok(false);
+    }
+
+    let mut dependency = if inherits {
+        let mut dependency = inline_table(&inherited(workspace, "dependencies", name)?)
+            .ok_or_else(|| format!("workspace.dependencies.{} must be a table", name))?;
+        let mut features = dependency
+            .get("features")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+        let added = own
+            .get("features")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter(|feature| !features.iter().any(|f| f.as_str() == feature.as_str()))
+            .cloned()
+            .collect::<Vec<Value>>();
+        features.extend(added);
+        if !features.is_empty() {
+            features.fmt();
+            dependency.insert("features", Value::Array(features));
+        }
+        dependency
+    } else {
+        own
+    };
+
+    if dependency.contains_key("path") {
+        if !dependency.contains_key("version") {
+            return Err(format!(
+                "dependency {} has a path but no version, so it can't be packaged. Add the \
+                 version it's published as, like version = \"^1.2\"",
+                name
+            ));
+        }
+        dependency.remove("path");
+        if registry != DEFAULT_REGISTRY && !dependency.contains_key("registry") {
+            dependency.insert("registry", registry.into());
+        }
+    }
+    dependency.fmt();
+    *item = Item::Value(Value::InlineTable(dependency));
+    Ok(true)
+}
+
+/// `key` of `[workspace.<table>]` in the workspace root
+fn inherited(
+    workspace: Option<&Document>,
+    table: &str,
+    key: &str,
+) -> std::result::Result<Item, String> {
+    workspace
+        .and_then(|workspace| workspace.get("workspace"))
+        .and_then(|workspace| workspace.get(table))
+        .and_then(|table| table.get(key))
+        .cloned()
+        .ok_or_else(|| {
+            format!(
+                "{}.{} is inherited, but the workspace doesn't set it",
+                table, key
+            )
+        })
+}
+
+fn inline_table(item: &Item) -> Option<InlineTable> {
+    match item {
+        Item::Value(Value::InlineTable(table)) => Some(table.clone()),
+        Item::Table(table) => Some(table.clone().into_inline_table()),
+        _ => None,
+    }
+}
+
+/// Writes `<prefix>.tar.gz` into `directory` holding `manifest` as tea.toml, along with the
+/// sources, headers and license files of the leaf in `leaf`, all under `<prefix>/`. Timestamps
+/// and permissions are fixed, so packaging the same files always gives the same archive
+pub fn write_archive(
+    leaf: &Path,
+    manifest: &Document,
+    prefix: &str,
+    directory: &Path,
+) -> Result<PathBuf> {
+    let mut files = Vec::new();
+    for source in ["src", "include"] {
+        WalkDir::new(leaf.join(source))
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .for_each(|entry| files.push(entry.into_path()));
+    }
+    let mut licenses = std::fs::read_dir(leaf)
+        .with_path(leaf)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            LICENSE_FILES
+                .iter()
+                .any(|license| name.to_uppercase().starts_with(license))
+        })
+        .collect::<Vec<PathBuf>>();
+    licenses.sort();
+    files.append(&mut licenses);
+
+    std::fs::create_dir_all(directory).with_path(directory)?;
+    let archive = directory.join(format!("{}.tar.gz", prefix));
+    let file = File::create(&archive).with_path(&archive)?;
+    let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+    let prefix = Path::new(prefix);
+    append(
+        &mut builder,
+        &prefix.join("tea.toml"),
+        manifest.to_string().as_bytes(),
+        &archive,
+    )?;
+    for file in files {
+        let contents = std::fs::read(&file).with_path(&file)?;
+        let name = file.strip_prefix(leaf).unwrap_or(&file);
+        append(&mut builder, &prefix.join(name), &contents, &archive)?;
+    }
+
+    builder
+        .into_inner()
+        .and_then(GzEncoder::finish)
+        .with_path(&archive)?;
+    Ok(archive)
+}
+
+fn append(
+    builder: &mut tar::Builder<GzEncoder<File>>,
+    path: &Path,
+    contents: &[u8],
+    archive: &Path,
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    builder
+        .append_data(&mut header, path, contents)
+        .with_path(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(manifest: &str, workspace: Option<&str>, registry: &str) -> Document {
+        let document = manifest.parse::<Document>().unwrap();
+        let workspace = workspace.map(|workspace| workspace.parse::<Document>().unwrap());
+        normalize_manifest(&document, workspace.as_ref(), registry).unwrap()
+    }
+
+    fn dependency(document: &Document, name: &str) -> String {
+        document["dependencies"][name].to_string().trim().to_owned()
+    }
+
+    #[test]
+    fn path_dependencies_come_from_the_registry_published_to() {
+        let manifest = r#"
+            [package]
+            name = "app"
+            version = "0.1.0"
+
+            [dependencies]
+            core = { path = "../core", version = "^0.1" }
+            util = { path = "../util", version = "^0.2", registry = "mirror" }
+        "#;
+
+        let normalized = normalize(manifest, None, "internal");
+        assert_eq!(
+            dependency(&normalized, "core"),
+            r#"{ version = "^0.1", registry = "internal" }"#
+        );
+        assert_eq!(
+            dependency(&normalized, "util"),
+            r#"{ version = "^0.2", registry = "mirror" }"#
+        );
+
+        let normalized = normalize(manifest, None, DEFAULT_REGISTRY);
+        assert_eq!(dependency(&normalized, "core"), r#"{ version = "^0.1" }"#);
+    }
+
+    #[test]
+    fn rewrites_path_workspace_and_git_dependencies() {
+        let workspace = r#"
+            [workspace]
+            members = ["leaves/*"]
+
+            [workspace.package]
+            version = "2.0.0"
+
+            [workspace.dependencies]
+            core = { path = "leaves/core", version = "^0.1", features = ["fast"] }
+            json = { version = "^1.4", registry = "internal" }
+        "#;
+        let manifest = r#"
+            [package]
+            name = "app"
+            version.workspace = true
+
+            [dependencies]
+            core = { workspace = true, features = ["small"] }
+            json = { workspace = true }
+            util = { path = "../util", version = "^0.2" }
+            parse = { git = "https://example.com/parse.git", tag = "v1.0" }
+
+            [dev-dependencies]
+            check = { path = "../check" }
+        "#;
+
+        let normalized = normalize(manifest, Some(workspace), DEFAULT_REGISTRY);
+        assert_eq!(normalized["package"]["version"].as_str(), Some("2.0.0"));
+        assert_eq!(
+            dependency(&normalized, "core"),
+            r#"{ version = "^0.1", features = ["fast", "small"] }"#
+        );
+        assert_eq!(
+            dependency(&normalized, "json"),
+            r#"{ version = "^1.4", registry = "internal" }"#
+        );
+        assert_eq!(dependency(&normalized, "util"), r#"{ version = "^0.2" }"#);
+        assert_eq!(
+            dependency(&normalized, "parse"),
+            r#"{ git = "https://example.com/parse.git", tag = "v1.0" }"#
+        );
+        assert!(normalized.get("dev-dependencies").is_none());
+    }
+
+    #[test]
+    fn rewrites_dependencies_of_features() {
+        let manifest = r#"
+            [package]
+            name = "app"
+            version = "0.1.0"
+            features = ["png"]
+
+            [dependencies.png]
+            libpng = { path = "../libpng", version = "^1.6" }
+        "#;
+
+        let normalized = normalize(manifest, None, DEFAULT_REGISTRY);
+        assert_eq!(
+            normalized["dependencies"]["png"]["libpng"]
+                .to_string()
+                .trim(),
+            r#"{ version = "^1.6" }"#
+        );
+    }
+
+    #[test]
+    fn path_dependencies_need_a_version() {
+        let document = r#"
+            [package]
+            name = "app"
+            version = "0.1.0"
+
+            [dependencies]
+            util = { path = "../util" }
+        "#
+        .parse::<Document>()
+        .unwrap();
+
+        let error = normalize_manifest(&document, None, DEFAULT_REGISTRY).unwrap_err();
+        assert!(error.starts_with("dependency util has a path but no version"));
+    }
+}
@@ -0,0 +1,613 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::{File, OpenOptions, TryLockError},
+    path::{Path, PathBuf},
+};
+
+use colored::Colorize;
+use flate2::read::GzDecoder;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    compiler::to_hex,
+    config::parse_registries,
+    error::{Error, IoContext, Result},
+    layout,
+    lockfile::LockMode,
+};
+
+/// The registry dependencies use when they don't name one
+pub const DEFAULT_REGISTRY: &str = "default";
+/// Marks an archive as completely unpacked, so one interrupted halfway is unpacked again
+const UNPACK_DONE: &str = ".tpot-unpacked";
+/// Locked in the registry root while a leaf is published to it
+const PUBLISH_LOCK: &str = ".tpot-lock";
+
+/// A leaf taken from a registry, by its name in `[registries]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegistrySource {
+    pub registry: String,
+    pub requirement: VersionReq,
+}
+
+/// A version of a leaf, unpacked from a registry into the cache
+#[derive(Debug, Clone)]
+pub struct RegistryRelease {
+    pub registry: String,
+    pub version: Version,
+    pub path: PathBuf,
+}
+
+/// The versions of a leaf a registry holds, read from `index/<name>.toml`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Index {
+    #[serde(default, rename = "release")]
+    pub releases: Vec<IndexEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub version: String,
+    /// The SHA-256 of the release's archive
+    pub checksum: String,
+}
+
+/// An entry of the index with its version parsed
+#[derive(Debug, Clone)]
+pub struct Release {
+    pub version: Version,
+    pub checksum: String,
+}
+
+/// A registry is a directory, usually on a network share, laid out as:
+///
+/// ```text
+/// index/<name>.toml                        every release of a leaf and the checksum of its archive
+/// leaves/<name>/<name>-<version>.tar.gz    the archive of each release, or a plain .tar
+/// .tpot-lock                               locked while a leaf is being published
+/// ```
+///
+/// Where each archive holds a single `<name>-<version>` directory with the leaf inside
+pub struct Registry {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl Registry {
+    pub fn index_path(&self, leaf: &str) -> PathBuf {
+        self.path.join("index").join(format!("{}.toml", leaf))
+    }
+
+    /// The archive of a release, published archives are compressed but plain ones are read too
+    pub fn archive_path(&self, leaf: &str, version: &Version) -> PathBuf {
+        let compressed = self.compressed_archive_path(leaf, version);
+        if compressed.is_file() {
+            return compressed;
+        }
+
+        compressed.with_extension("")
+    }
+
+    fn compressed_archive_path(&self, leaf: &str, version: &Version) -> PathBuf {
+        self.path
+            .join("leaves")
+            .join(leaf)
+            .join(format!("{}-{}.tar.gz", leaf, version))
+    }
+
+    /// Fails if `version` of the leaf is already in the registry, returning its index otherwise
+    pub fn unpublished(&self, leaf: &str, version: &Version) -> Result<Index> {
+        let index = self.index(leaf)?;
+        let archive = self.compressed_archive_path(leaf, version);
+        let indexed = index
+            .releases
+            .iter()
+            .any(|entry| Version::parse(&entry.version).is_ok_and(|v| v == *version));
+        if indexed || archive.exists() || archive.with_extension("").exists() {
+            return Err(Error::Usage(format!(
+                "{} {} is already in registry {}, bump package.version to publish it again",
+                leaf, version, self.name
+            )));
+        }
+
+        Ok(index)
+    }
+
+    /// Copies a packaged leaf into the registry and adds it to the index. A version that's
+    /// already in the registry is never overwritten
+    pub fn publish(&self, leaf: &str, version: &Version, archive: &Path) -> Result<()> {
+        // Held until the index is written, so two publishes at once can't both find the version
+        // unpublished or drop each other's release from the index
+        let _lock = self.lock()?;
+        let mut index = self.unpublished(leaf, version)?;
+        let published = self.compressed_archive_path(leaf, version);
+        let contents = std::fs::read(archive).with_path(archive)?;
+        index.releases.push(IndexEntry {
+            version: version.to_string(),
+            checksum: to_hex(&Sha256::digest(&contents)),
+        });
+        let index_text = toml::to_string(&index).map_err(|error| {
+            Error::manifest(&self.index_path(leaf), format!("couldn't write: {}", error))
+        })?;
+
+        // Written next to their destination and renamed, so a half written file is never seen
+        write_atomically(&published, &contents)?;
+        write_atomically(&self.index_path(leaf), index_text.as_bytes())
+    }
+
+    /// Takes the registry's lock, waiting for whoever holds it. It's released when the file is
+    /// dropped
+    fn lock(&self) -> Result<File> {
+        std::fs::create_dir_all(&self.path).with_path(&self.path)?;
+        let path = self.path.join(PUBLISH_LOCK);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_path(&path)?;
+
+        match file.try_lock() {
+            Ok(()) => return Ok(file),
+            Err(TryLockError::WouldBlock) => {}
+            Err(TryLockError::Error(error)) => return Err(error).with_path(&path),
+        }
+        println!(
+            "\r{:13} {} for the lock on registry {}",
+            String::new(),
+            "Waiting".green().bold(),
+            self.name
+        );
+        file.lock().with_path(&path)?;
+        Ok(file)
+    }
+
+    pub fn index(&self, leaf: &str) -> Result<Index> {
+        let path = self.index_path(leaf);
+        if !path.is_file() {
+            return Ok(Index::default());
+        }
+
+        let text = std::fs::read_to_string(&path).with_path(&path)?;
+        toml::from_str(&text).map_err(|error| Error::manifest(&path, error.message().trim_end()))
+    }
+
+    /// Every release of a leaf in the index
+    pub fn releases(&self, leaf: &str) -> Result<Vec<Release>> {
+        self.index(leaf)?
+            .releases
+            .into_iter()
+            .map(|entry| {
+                let version = Version::parse(&entry.version).map_err(|error| {
+                    Error::manifest(
+                        &self.index_path(leaf),
+                        format!("{} isn't a valid version: {}", entry.version, error),
+                    )
+                })?;
+                Ok(Release {
+                    version,
+                    checksum: entry.checksum,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Who asked for which versions of a leaf, kept to explain conflicts
+#[derive(Debug, Clone)]
+struct Requirement {
+    requirement: VersionReq,
+    /// The names of the leaves depending on each other down to the one with the requirement
+    chain: Vec<String>,
+}
+
+/// Picks a single version of each registry leaf for the whole graph, the newest one matching
+/// every requirement on it, and unpacks it into `<tpot home>/registry`. Versions pinned by
+/// tea.lock are kept while they still match
+#[derive(Debug, Default)]
+pub struct RegistryCache {
+    pub mode: LockMode,
+    /// The registries configured in the project root, relative paths are relative to it
+    pub registries: HashMap<String, PathBuf>,
+    /// The version locked for each leaf, by `registry+<registry> <name>`
+    pub pinned: HashMap<String, Version>,
+    /// The version picked for each leaf, carried over when the graph is loaded again
+    chosen: HashMap<String, Version>,
+    requirements: HashMap<String, Vec<Requirement>>,
+    /// Set when a later requirement didn't match a version picked earlier
+    changed: bool,
+    releases: HashMap<String, Vec<Release>>,
+}
+
+impl RegistryCache {
+    /// Forgets the requirements of the last attempt at loading the graph, keeping the versions
+    /// it picked
+    pub fn start(&mut self) {
+        self.requirements.clear();
+        self.changed = false;
+    }
+
+    /// Whether a version was changed while loading the graph, in which case leaves loaded before
+    /// the change depend on the wrong version and the graph has to be loaded again
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    /// Finds the release of `name` used by the graph, `chain` holds the names of the leaves
+    /// leading to the one that depends on it
+    pub fn fetch(
+        &mut self,
+        name: &str,
+        source: &RegistrySource,
+        chain: &[String],
+    ) -> Result<RegistryRelease> {
+        let key = format!("registry+{} {}", source.registry, name);
+        self.requirements
+            .entry(key.clone())
+            .or_default()
+            .push(Requirement {
+                requirement: source.requirement.clone(),
+                chain: chain.to_owned(),
+            });
+
+        let registry = self.registry(&source.registry)?;
+        let cache = layout::tpot_home()?.join("registry").join(format!(
+            "{}-{}",
+            registry.name,
+            short_hash(&registry.path)
+        ));
+
+        let version = match self.chosen.get(&key) {
+            Some(version) if source.requirement.matches(version) => version.clone(),
+            previous => {
+                self.changed |= previous.is_some();
+                let version = self.choose(&registry, &cache, name, &key)?;
+                self.chosen.insert(key.clone(), version.clone());
+                version
+            }
+        };
+
+        if !unpacked(&cache, name, &version) {
+            if self.mode == LockMode::Frozen {
+                return Err(frozen(name, &version, &registry));
+            }
+            let release = self
+                .releases(&registry, name, &key)?
+                .iter()
+                .find(|release| release.version == version)
+                .cloned()
+                .ok_or_else(|| {
+                    Error::Resolution(format!(
+                        "registry {} no longer has {} {}",
+                        registry.name, name, version
+                    ))
+                })?;
+            unpack(&registry, &cache, name, &release)?;
+        }
+
+        Ok(RegistryRelease {
+            registry: registry.name,
+            path: cache.join(format!("{}-{}", name, version)),
+            version,
+        })
+    }
+
+    /// The pinned version if it matches every requirement so far, otherwise the newest release
+    /// that does
+    fn choose(
+        &mut self,
+        registry: &Registry,
+        cache: &Path,
+        name: &str,
+        key: &str,
+    ) -> Result<Version> {
+        let requirements = self.requirements[key].clone();
+        let matches = |version: &Version| {
+            requirements
+                .iter()
+                .all(|requirement| requirement.requirement.matches(version))
+        };
+
+        let pinned = self
+            .pinned
+            .get(key)
+            .filter(|version| matches(version))
+            .cloned();
+        if let Some(pinned) = &pinned {
+            // A pinned version that's already unpacked doesn't need the registry at all
+            if unpacked(cache, name, pinned) {
+                return Ok(pinned.clone());
+            }
+        }
+        if self.mode == LockMode::Frozen {
+            return Err(frozen(name, &requirements[0].requirement, registry));
+        }
+
+        let releases = self.releases(registry, name, key)?;
+        if let Some(pinned) =
+            pinned.filter(|pinned| releases.iter().any(|release| release.version == *pinned))
+        {
+            return Ok(pinned);
+        }
+
+        releases
+            .iter()
+            .map(|release| &release.version)
+            .filter(|version| matches(version))
+            .max()
+            .cloned()
+            .ok_or_else(|| conflict(registry, name, &requirements, releases))
+    }
+
+    /// The releases of a leaf, the index is only read once per run
+    fn releases(&mut self, registry: &Registry, name: &str, key: &str) -> Result<&[Release]> {
+        if !self.releases.contains_key(key) {
+            let releases = registry.releases(name)?;
+            self.releases.insert(key.to_owned(), releases);
+        }
+
+        Ok(&self.releases[key])
+    }
+
+    /// Looks a registry up in the project, then in `<tpot home>/config.toml`
+    pub fn registry(&self, name: &str) -> Result<Registry> {
+        if let Some(path) = self.registries.get(name) {
+            return Ok(Registry {
+                name: name.to_owned(),
+                path: path.clone(),
+            });
+        }
+
+        let config = layout::tpot_home()?.join("config.toml");
+        let user = match std::fs::read_to_string(&config) {
+            Ok(text) => {
+                let document = text
+                    .parse()
+                    .map_err(|error| Error::manifest(&config, error))?;
+                parse_registries(&document).map_err(|message| Error::manifest(&config, message))?
+            }
+            Err(_) => HashMap::new(),
+        };
+
+        user.get(name)
+            .map(|path| Registry {
+                name: name.to_owned(),
+                path: path.clone(),
+            })
+            .ok_or_else(|| {
+                Error::Resolution(format!(
+                    "no registry named {}, add it to [registries] in tea.toml or {}",
+                    name,
+                    config.display()
+                ))
+            })
+    }
+}
+
+fn frozen<T: Display>(name: &str, version: &T, registry: &Registry) -> Error {
+    Error::Resolution(format!(
+        "{} {} from registry {} isn't in the cache, and --frozen doesn't allow fetching it",
+        name, version, registry.name
+    ))
+}
+
+/// Explains why no release of a leaf matches, listing every leaf that asked for it
+fn conflict(
+    registry: &Registry,
+    name: &str,
+    requirements: &[Requirement],
+    releases: &[Release],
+) -> Error {
+    let mut versions = releases
+        .iter()
+        .map(|release| &release.version)
+        .collect::<Vec<&Version>>();
+    versions.sort();
+    let versions = if versions.is_empty() {
+        "none".to_owned()
+    } else {
+        versions
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+
+    let message = match requirements {
+        [requirement] => format!(
+            "registry {} has no release of {} matching {}, its releases are: {}",
+            registry.name, name, requirement.requirement, versions
+        ),
+        _ => {
+            let chains = requirements
+                .iter()
+                .map(|requirement| {
+                    format!(
+                        "  {} -> {} {}",
+                        requirement.chain.join(" -> "),
+                        name,
+                        requirement.requirement
+                    )
+                })
+                .collect::<Vec<String>>();
+            format!(
+                "no release of {} in registry {} matches every leaf depending on it:\n{}\n\n\
+                 its releases are: {}",
+                name,
+                registry.name,
+                chains.join("\n"),
+                versions
+            )
+        }
+    };
+
+    Error::Resolution(message)
+}
+
+fn unpacked(cache: &Path, name: &str, version: &Version) -> bool {
+    cache
+        .join(format!("{}-{}", name, version))
+        .join(UNPACK_DONE)
+        .is_file()
+}
+
+/// Checks a release's archive against the index and unpacks it into the cache
+fn unpack(registry: &Registry, cache: &Path, name: &str, release: &Release) -> Result<()> {
+    if unpacked(cache, name, &release.version) {
+        return Ok(());
+    }
+
+    println!(
+        "\r{:13} {} {} v{} ({})",
+        String::new(),
+        "Unpacking".green().bold(),
+        name,
+        release.version,
+        registry.name
+    );
+    let archive = registry.archive_path(name, &release.version);
+    let contents = std::fs::read(&archive).with_path(&archive)?;
+    let checksum = to_hex(&Sha256::digest(&contents));
+    if checksum != release.checksum {
+        return Err(Error::Resolution(format!(
+            "{} doesn't match the checksum in the index of registry {}, it may be corrupt",
+            archive.display(),
+            registry.name
+        )));
+    }
+
+    let directory = cache.join(format!("{}-{}", name, release.version));
+    if directory.exists() {
+        std::fs::remove_dir_all(&directory).with_path(&directory)?;
+    }
+    unpack_archive(&archive, &contents, cache)?;
+    if !directory.join("tea.toml").is_file() {
+        return Err(Error::Resolution(format!(
+            "{} doesn't contain {}-{}/tea.toml",
+            archive.display(),
+            name,
+            release.version
+        )));
+    }
+
+    std::fs::write(directory.join(UNPACK_DONE), &release.checksum).with_path(&directory)
+}
+
+/// Unpacks a `.tar` or `.tar.gz` into `destination`
+pub fn unpack_archive(archive: &Path, contents: &[u8], destination: &Path) -> Result<()> {
+    std::fs::create_dir_all(destination).with_path(destination)?;
+    let unpacked = if archive
+        .extension()
+        .is_some_and(|extension| extension == "gz")
+    {
+        tar::Archive::new(GzDecoder::new(contents)).unpack(destination)
+    } else {
+        tar::Archive::new(contents).unpack(destination)
+    };
+
+    unpacked.with_path(archive)
+}
+
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).with_path(parent)?;
+    }
+
+    let temporary = path.with_extension("partial");
+    std::fs::write(&temporary, contents).with_path(&temporary)?;
+    std::fs::rename(&temporary, path).with_path(path)
+}
+
+/// Keeps the caches of registries with the same name but different paths apart
+fn short_hash(path: &Path) -> String {
+    to_hex(&Sha256::digest(path.to_string_lossy().as_bytes())[..4])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "registry+default core";
+
+    /// Picks a version of `core` from `releases` for `requirements`, without touching the disk
+    fn choose(releases: &[&str], requirements: &[&str], pinned: Option<&str>) -> Result<Version> {
+        let mut cache = RegistryCache::default();
+        let releases = releases
+            .iter()
+            .map(|version| Release {
+                version: Version::parse(version).unwrap(),
+                checksum: String::new(),
+            })
+            .collect();
+        cache.releases.insert(KEY.to_owned(), releases);
+        let requirements = requirements
+            .iter()
+            .map(|requirement| Requirement {
+                requirement: VersionReq::parse(requirement).unwrap(),
+                chain: vec!["app".to_owned()],
+            })
+            .collect();
+        cache.requirements.insert(KEY.to_owned(), requirements);
+        if let Some(pinned) = pinned {
+            cache
+                .pinned
+                .insert(KEY.to_owned(), Version::parse(pinned).unwrap());
+        }
+
+        let registry = Registry {
+            name: DEFAULT_REGISTRY.to_owned(),
+            path: PathBuf::from("registry"),
+        };
+        cache.choose(&registry, Path::new("cache"), "core", KEY)
+    }
+
+    #[test]
+    fn picks_the_highest_compatible_version() {
+        let releases = ["0.9.0", "1.0.0", "1.2.0", "1.10.1", "2.0.0"];
+        let version = choose(&releases, &["^1.0"], None).unwrap();
+        assert_eq!(version, Version::new(1, 10, 1));
+
+        let version = choose(&releases, &["^1.0", "<1.5"], None).unwrap();
+        assert_eq!(version, Version::new(1, 2, 0));
+    }
+
+    #[test]
+    fn skips_pre_releases() {
+        let releases = ["1.0.0", "1.1.0-beta.1", "2.0.0-rc.1"];
+        let version = choose(&releases, &["^1.0"], None).unwrap();
+        assert_eq!(version, Version::new(1, 0, 0));
+
+        let version = choose(&releases, &["*"], None).unwrap();
+        assert_eq!(version, Version::new(1, 0, 0));
+    }
+
+    #[test]
+    fn keeps_a_pinned_version_that_still_matches() {
+        let releases = ["1.0.0", "1.2.0"];
+        let version = choose(&releases, &["^1.0"], Some("1.0.0")).unwrap();
+        assert_eq!(version, Version::new(1, 0, 0));
+
+        let version = choose(&releases, &["^1.1"], Some("1.0.0")).unwrap();
+        assert_eq!(version, Version::new(1, 2, 0));
+    }
+
+    #[test]
+    fn no_matching_version_is_a_resolution_error() {
+        let error = choose(&["1.0.0", "1.2.0"], &["^2.0"], None).unwrap_err();
+        let Error::Resolution(message) = error else {
+            panic!("expected a resolution error, got {:?}", error);
+        };
+        assert_eq!(
+            message,
+            "registry default has no release of core matching ^2.0, its releases are: 1.0.0, 1.2.0"
+        );
+
+        let error = choose(&["1.0.0", "2.0.0"], &["^1.0", "^2.0"], None).unwrap_err();
+        assert!(
+            matches!(error, Error::Resolution(message) if message.contains("app -> core ^1.0"))
+        );
+    }
+}
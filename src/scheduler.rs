@@ -0,0 +1,218 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Mutex, PoisonError,
+};
+
+use crate::error::Error;
+
+pub type JobId = usize;
+
+/// A graph of jobs where each job starts as soon as every job it depends on has finished
+pub struct JobGraph<T> {
+    jobs: Vec<T>,
+    dependents: Vec<Vec<JobId>>,
+    dependencies: Vec<usize>,
+}
+
+impl<T: Sync> JobGraph<T> {
+    pub fn new() -> Self {
+        Self {
+            jobs: Vec::new(),
+            dependents: Vec::new(),
+            dependencies: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, job: T, dependencies: &[JobId]) -> JobId {
+        let id = self.jobs.len();
+        self.jobs.push(job);
+        self.dependents.push(Vec::new());
+        self.dependencies.push(dependencies.len());
+        dependencies
+            .iter()
+            .for_each(|dependency| self.dependents[*dependency].push(id));
+
+        id
+    }
+
+    pub fn jobs(&self) -> &[T] {
+        &self.jobs
+    }
+
+    /// Runs every job on `pool`. Once a job fails no new jobs are started, and the errors of
+    /// every job that failed are returned
+    pub fn run<F, E>(&self, pool: &rayon::ThreadPool, run: F) -> Result<(), Vec<E>>
+    where
+        F: Fn(&T) -> Result<(), E> + Sync,
+        E: Send,
+    {
+        let remaining: Vec<AtomicUsize> = self
+            .dependencies
+            .iter()
+            .map(|count| AtomicUsize::new(*count))
+            .collect();
+        let failed = AtomicBool::new(false);
+        let errors = Mutex::new(Vec::new());
+        let state = RunState {
+            remaining: &remaining,
+            failed: &failed,
+            errors: &errors,
+            run: &run,
+        };
+
+        pool.scope(|scope| {
+            self.dependencies
+                .iter()
+                .enumerate()
+                .filter(|(_, count)| **count == 0)
+                .for_each(|(id, _)| self.spawn(scope, id, state));
+        });
+
+        let errors = errors.into_inner().unwrap_or_else(PoisonError::into_inner);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn spawn<'a, F, E>(&'a self, scope: &rayon::Scope<'a>, id: JobId, state: RunState<'a, F, E>)
+    where
+        F: Fn(&T) -> Result<(), E> + Sync,
+        E: Send,
+    {
+        scope.spawn(move |scope| {
+            if state.failed.load(Ordering::SeqCst) {
+                return;
+            }
+            if let Err(error) = (state.run)(&self.jobs[id]) {
+                state.failed.store(true, Ordering::SeqCst);
+                state
+                    .errors
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(error);
+                return;
+            }
+
+            self.dependents[id]
+                .iter()
+                .filter(|dependent| {
+                    state.remaining[**dependent].fetch_sub(1, Ordering::SeqCst) == 1
+                })
+                .for_each(|dependent| self.spawn(scope, *dependent, state));
+        });
+    }
+}
+
+/// A pool of at most `threads` threads to run jobs on
+pub fn pool(threads: usize) -> Result<rayon::ThreadPool, Error> {
+    let threads = threads.max(1);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|error| Error::Threads {
+            count: threads,
+            message: error.to_string(),
+        })
+}
+
+/// Shared between every job of a single run
+struct RunState<'a, F, E> {
+    remaining: &'a [AtomicUsize],
+    failed: &'a AtomicBool,
+    errors: &'a Mutex<Vec<E>>,
+    run: &'a F,
+}
+
+impl<F, E> Clone for RunState<'_, F, E> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<F, E> Copy for RunState<'_, F, E> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn runs_jobs_after_their_dependencies() {
+        // 0 <- 1 <- 3, 0 <- 2 <- 3
+        let mut graph = JobGraph::new();
+        let core = graph.add(0, &[]);
+        let render = graph.add(1, &[core]);
+        let audio = graph.add(2, &[core]);
+        graph.add(3, &[render, audio]);
+
+        let finished = Mutex::new(Vec::new());
+        let result = graph.run(&pool(4).unwrap(), |job: &usize| {
+            thread::sleep(Duration::from_millis(5 * (3 - *job as u64)));
+            finished.lock().unwrap().push(*job);
+            Ok::<(), ()>(())
+        });
+
+        assert_eq!(result, Ok(()));
+        let finished = finished.into_inner().unwrap();
+        assert_eq!((finished[0], finished[3]), (0, 3));
+        assert_eq!(finished.len(), 4);
+    }
+
+    #[test]
+    fn starts_no_jobs_after_one_fails() {
+        let mut graph = JobGraph::new();
+        let bad = graph.add("bad", &[]);
+        graph.add("needs bad", &[bad]);
+        let slow = graph.add("slow", &[]);
+        graph.add("after slow", &[slow]);
+
+        let started = Mutex::new(Vec::new());
+        let slow_started = AtomicBool::new(false);
+        let result = graph.run(&pool(2).unwrap(), |job: &&str| {
+            started.lock().unwrap().push(*job);
+            match *job {
+                "bad" => {
+                    while !slow_started.load(Ordering::SeqCst) {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    return Err(job.to_string());
+                }
+                // Still running when bad fails, so it finishes but nothing after it starts
+                "slow" => {
+                    slow_started.store(true, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(50));
+                }
+                _ => {}
+            }
+            Ok(())
+        });
+
+        assert_eq!(result, Err(vec!["bad".to_owned()]));
+        let mut started = started.into_inner().unwrap();
+        started.sort();
+        assert_eq!(started, ["bad", "slow"]);
+    }
+
+    #[test]
+    fn runs_at_most_as_many_jobs_as_threads() {
+        let mut graph = JobGraph::new();
+        (0..16).for_each(|job| {
+            graph.add(job, &[]);
+        });
+
+        let running = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        let result = graph.run(&pool(2).unwrap(), |_| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(5));
+            running.fetch_sub(1, Ordering::SeqCst);
+            Ok::<(), ()>(())
+        });
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(most.into_inner(), 2);
+    }
+}
@@ -0,0 +1,249 @@
+use std::{ffi::OsString, path::Path, process::Command};
+
+use crate::config::ToolchainConfig;
+
+pub const DEFAULT_COMPILER: &str = "tcc";
+/// Every supported compiler takes the same flags for these
+pub const STANDARD_FLAG: &str = "-std=c11";
+pub const DEBUG_FLAG: &str = "-g";
+/// The first tcc release that writes depfiles with `-MD -MF`
+const TCC_DEPFILE_VERSION: (u32, u32, u32) = (0, 9, 27);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolchainKind {
+    Tcc,
+    Gcc,
+    Clang,
+}
+
+impl ToolchainKind {
+    /// Guesses the flavour from the program name, so `CC=/usr/bin/clang-15` or
+    /// `CC=x86_64-w64-mingw32-gcc` work, anything unrecognised is assumed to be gcc compatible.
+    /// Only whole `-` separated parts of the name count, so `distcc` or `ccache` aren't tcc
+    pub fn detect(program: &str) -> Self {
+        let name = Path::new(program)
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let mut parts = name.split('-');
+
+        if parts.clone().any(|part| part.starts_with("clang")) {
+            Self::Clang
+        } else if parts.any(|part| part == "tcc") {
+            Self::Tcc
+        } else {
+            Self::Gcc
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Toolchain {
+    pub kind: ToolchainKind,
+    pub compiler: String,
+    pub archiver: Option<String>,
+    pub version: String,
+    /// Whether the compiler writes depfiles, without them every object is always recompiled
+    pub depfiles: bool,
+}
+
+impl Toolchain {
+    /// Picks the compiler from `--compiler`, then `CC`, then `[toolchain]` in tea.toml, then tcc.
+    /// The archiver comes from `AR`, then `[toolchain]`, then the toolchain's default
+    pub fn resolve(flag: Option<&str>, config: &ToolchainConfig) -> Self {
+        let compiler = flag
+            .map(ToOwned::to_owned)
+            .or_else(|| std::env::var("CC").ok().filter(|cc| !cc.is_empty()))
+            .or_else(|| config.compiler.clone())
+            .unwrap_or_else(|| DEFAULT_COMPILER.to_owned());
+        let archiver = std::env::var("AR")
+            .ok()
+            .filter(|ar| !ar.is_empty())
+            .or_else(|| config.archiver.clone());
+
+        Self::new(&compiler, archiver)
+    }
+
+    pub fn new(compiler: &str, archiver: Option<String>) -> Self {
+        let kind = ToolchainKind::detect(compiler);
+        let version = Self::query_version(kind, compiler);
+        // A tcc whose banner can't be read is assumed to be recent enough
+        let depfiles = match kind {
+            ToolchainKind::Tcc => {
+                tcc_version(&version).is_none_or(|version| version >= TCC_DEPFILE_VERSION)
+            }
+            ToolchainKind::Gcc | ToolchainKind::Clang => true,
+        };
+
+        Self {
+            kind,
+            compiler: compiler.to_owned(),
+            archiver,
+            version,
+            depfiles,
+        }
+    }
+
+    /// The compiler's version banner, so upgrading the toolchain invalidates every object
+    fn query_version(kind: ToolchainKind, compiler: &str) -> String {
+        let flag = match kind {
+            ToolchainKind::Tcc => "-v",
+            ToolchainKind::Gcc | ToolchainKind::Clang => "--version",
+        };
+
+        Command::new(compiler)
+            .arg(flag)
+            .output()
+            .map(|output| {
+                let mut version = String::from_utf8_lossy(&output.stdout).into_owned();
+                version.push_str(&String::from_utf8_lossy(&output.stderr));
+                version
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn command(&self) -> Command {
+        Command::new(&self.compiler)
+    }
+
+    /// tcc has a built in archiver, everything else uses the system `ar`
+    pub fn archive_command(&self) -> Command {
+        match (&self.archiver, self.kind) {
+            (Some(archiver), _) => Command::new(archiver),
+            (None, ToolchainKind::Tcc) => {
+                let mut cmd = Command::new(&self.compiler);
+                cmd.arg("-ar");
+                cmd
+            }
+            (None, ToolchainKind::Gcc | ToolchainKind::Clang) => Command::new("ar"),
+        }
+    }
+
+    pub fn depfile_flags(&self, depfile: &Path) -> Vec<OsString> {
+        if !self.depfiles {
+            return Vec::new();
+        }
+
+        vec!["-MD".into(), "-MF".into(), depfile.as_os_str().to_owned()]
+    }
+
+    /// tcc has no sanitizers, but its bounds checker covers most of what asan would catch.
+    /// Returns the name of the first unsupported sanitizer on failure
+    pub fn sanitizer_flags(&self, sanitizers: &[String]) -> Result<Vec<String>, String> {
+        if sanitizers.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        match self.kind {
+            ToolchainKind::Tcc => {
+                let mut flags = sanitizers
+                    .iter()
+                    .map(|sanitizer| match sanitizer.as_str() {
+                        "address" | "bounds" => Ok("-b".to_owned()),
+                        _ => Err(sanitizer.clone()),
+                    })
+                    .collect::<Result<Vec<String>, String>>()?;
+                flags.dedup();
+                Ok(flags)
+            }
+            ToolchainKind::Gcc | ToolchainKind::Clang => {
+                Ok(vec![format!("-fsanitize={}", sanitizers.join(","))])
+            }
+        }
+    }
+}
+
+pub fn optimization_flag(level: &str) -> String {
+    format!("-O{}", level)
+}
+
+pub fn include_flag(path: &Path) -> String {
+    format!("-I{}", path.display())
+}
+
+pub fn define_flag(name: &str, value: Option<&str>) -> String {
+    match value {
+        Some(value) => format!("-D{}={}", name, value),
+        None => format!("-D{}", name),
+    }
+}
+
+pub fn library_flag(name: &str) -> String {
+    format!("-l{}", name)
+}
+
+/// The version in tcc's banner, like `tcc version 0.9.27 (x86_64 Linux)`
+fn tcc_version(banner: &str) -> Option<(u32, u32, u32)> {
+    let version = banner
+        .split_whitespace()
+        .skip_while(|word| *word != "version")
+        .nth(1)?;
+    let mut parts = version.split('.').map(|part| {
+        let digits = part
+            .chars()
+            .take_while(char::is_ascii_digit)
+            .collect::<String>();
+        digits.parse::<u32>().ok()
+    });
+
+    Some((
+        parts.next()??,
+        parts.next()??,
+        parts.next().flatten().unwrap_or(0),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_tcc_version() {
+        assert_eq!(
+            tcc_version("tcc version 0.9.27 (x86_64 Linux)\n"),
+            Some((0, 9, 27))
+        );
+        assert_eq!(
+            tcc_version("tcc version 0.9.28rc 2024-02-05 mob@08a4c52d (x86_64 Linux)"),
+            Some((0, 9, 28))
+        );
+        assert_eq!(tcc_version("tcc version 0.9.26"), Some((0, 9, 26)));
+        assert_eq!(tcc_version(""), None);
+    }
+
+    #[test]
+    fn detects_the_toolchain_from_the_program_name() {
+        let detect = |program| ToolchainKind::detect(program);
+        assert_eq!(detect("tcc"), ToolchainKind::Tcc);
+        assert_eq!(
+            detect("/opt/tcc/bin/i386-win32-tcc.exe"),
+            ToolchainKind::Tcc
+        );
+        assert_eq!(detect("/usr/bin/clang-15"), ToolchainKind::Clang);
+        assert_eq!(detect("x86_64-linux-gnu-clang"), ToolchainKind::Clang);
+        assert_eq!(detect("x86_64-w64-mingw32-gcc"), ToolchainKind::Gcc);
+        assert_eq!(detect("distcc"), ToolchainKind::Gcc);
+        assert_eq!(detect("/usr/lib/ccache/cc"), ToolchainKind::Gcc);
+    }
+
+    #[test]
+    fn passes_the_tcc_bounds_checker_once() {
+        let toolchain = Toolchain {
+            kind: ToolchainKind::Tcc,
+            compiler: "tcc".to_owned(),
+            archiver: None,
+            version: String::new(),
+            depfiles: true,
+        };
+        let sanitizers = ["address".to_owned(), "bounds".to_owned()];
+        assert_eq!(
+            toolchain.sanitizer_flags(&sanitizers),
+            Ok(vec!["-b".to_owned()])
+        );
+        let sanitizers = ["address".to_owned(), "thread".to_owned()];
+        assert_eq!(
+            toolchain.sanitizer_flags(&sanitizers),
+            Err("thread".to_owned())
+        );
+    }
+}
@@ -0,0 +1,221 @@
+use std::path::Path;
+
+use crate::{
+    config::BASE_FEATURES,
+    diagnostic,
+    error::{Error, Result},
+    graph::{canonical_path, relative_path, LeafGraph, LeafId},
+    Leaf, Source,
+};
+
+/// The edges `tpot tree` follows from each leaf, either to its dependencies or, inverted, to the
+/// leaves depending on it
+pub struct Edges {
+    edges: Vec<Vec<LeafId>>,
+    inverted: bool,
+}
+
+impl Edges {
+    pub fn dependencies(leaves: &LeafGraph) -> Self {
+        let edges = leaves
+            .leaves()
+            .iter()
+            .map(|leaf| unique(leaf.dependencies.iter().copied()))
+            .collect();
+
+        Self {
+            edges,
+            inverted: false,
+        }
+    }
+
+    pub fn dependents(leaves: &LeafGraph) -> Self {
+        let edges = (0..leaves.leaves().len())
+            .map(|id| {
+                unique(
+                    leaves
+                        .leaves()
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, leaf)| leaf.dependencies.contains(&id))
+                        .map(|(dependent, _)| dependent),
+                )
+            })
+            .collect();
+
+        Self {
+            edges,
+            inverted: true,
+        }
+    }
+}
+
+fn unique(ids: impl Iterator<Item = LeafId>) -> Vec<LeafId> {
+    let mut unique = Vec::new();
+    ids.for_each(|id| {
+        if !unique.contains(&id) {
+            unique.push(id);
+        }
+    });
+    unique
+}
+
+/// Every build of the leaf called `name`
+pub fn named(leaves: &LeafGraph, name: &str) -> Result<Vec<LeafId>> {
+    let ids = leaves
+        .leaves()
+        .iter()
+        .enumerate()
+        .filter(|(_, leaf)| leaf.config.package.name == name)
+        .map(|(id, _)| id)
+        .collect::<Vec<LeafId>>();
+    if !ids.is_empty() {
+        return Ok(ids);
+    }
+
+    let mut message = format!("no leaf named {} in the graph", name);
+    let names = leaves
+        .leaves()
+        .iter()
+        .map(|leaf| leaf.config.package.name.as_str());
+    if let Some(suggestion) = diagnostic::suggest(name, names) {
+        message.push_str(&format!(", did you mean {}?", suggestion));
+    }
+    Err(Error::Usage(message))
+}
+
+/// Every build of each leaf that's built more than once, from another path or version
+pub fn duplicates(leaves: &LeafGraph) -> Vec<LeafId> {
+    let mut duplicates = (0..leaves.leaves().len())
+        .filter(|id| {
+            let name = &leaves[*id].config.package.name;
+            leaves
+                .leaves()
+                .iter()
+                .filter(|leaf| leaf.config.package.name == *name)
+                .count()
+                > 1
+        })
+        .collect::<Vec<LeafId>>();
+    duplicates.sort_by_key(|id| &leaves[*id].config.package.name);
+    duplicates
+}
+
+/// Draws the graph as a tree below each of `starts`. A leaf already drawn in the same tree is
+/// marked with `(*)` instead of being drawn again
+pub fn text(leaves: &LeafGraph, project_root: &Path, starts: &[LeafId], edges: &Edges) -> String {
+    let root = canonical_path(project_root);
+    let mut output = String::new();
+    for (index, start) in starts.iter().enumerate() {
+        if index > 0 {
+            output.push('\n');
+        }
+        output.push_str(&describe(&leaves[*start], &root));
+        output.push('\n');
+
+        let mut drawn = vec![false; leaves.leaves().len()];
+        drawn[*start] = true;
+        draw(leaves, &root, edges, *start, "", &mut drawn, &mut output);
+    }
+
+    output
+}
+
+fn draw(
+    leaves: &LeafGraph,
+    root: &Path,
+    edges: &Edges,
+    id: LeafId,
+    prefix: &str,
+    drawn: &mut [bool],
+    output: &mut String,
+) {
+    let children = &edges.edges[id];
+    for (index, child) in children.iter().enumerate() {
+        let last = index + 1 == children.len();
+        output.push_str(prefix);
+        output.push_str(if last { "└── " } else { "├── " });
+        output.push_str(&describe(&leaves[*child], root));
+
+        if drawn[*child] && !edges.edges[*child].is_empty() {
+            output.push_str(" (*)\n");
+            continue;
+        }
+        output.push('\n');
+        drawn[*child] = true;
+
+        let prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+        draw(leaves, root, edges, *child, &prefix, drawn, output);
+    }
+}
+
+/// Writes the leaves reachable from `starts` as a graphviz digraph, with edges pointing from each
+/// leaf to its dependencies
+pub fn dot(leaves: &LeafGraph, starts: &[LeafId], edges: &Edges) -> String {
+    let mut reachable = vec![false; leaves.leaves().len()];
+    let mut stack = starts.to_owned();
+    while let Some(id) = stack.pop() {
+        if !reachable[id] {
+            reachable[id] = true;
+            stack.extend_from_slice(&edges.edges[id]);
+        }
+    }
+
+    let mut output = "digraph leaves {\n".to_owned();
+    for (id, leaf) in leaves.leaves().iter().enumerate() {
+        if reachable[id] {
+            let label = label(leaf).replace('\\', "\\\\").replace('"', "\\\"");
+            output.push_str(&format!("    n{} [label=\"{}\"];\n", id, label));
+        }
+    }
+    for (id, children) in edges.edges.iter().enumerate() {
+        for child in children.iter().filter(|_| reachable[id]) {
+            let (from, to) = if edges.inverted {
+                (child, &id)
+            } else {
+                (&id, child)
+            };
+            output.push_str(&format!("    n{} -> n{};\n", from, to));
+        }
+    }
+    output.push_str("}\n");
+
+    output
+}
+
+/// The leaf's name, version and features, like `core v0.1.0 +fast`
+fn label(leaf: &Leaf) -> String {
+    let mut label = format!(
+        "{} v{}",
+        leaf.config.package.name, leaf.config.package.version
+    );
+    // Platform features are enabled on every leaf, so they're left out
+    leaf.features
+        .iter()
+        .filter(|feature| feature.enabled)
+        .filter(|feature| !BASE_FEATURES.contains(&feature.name.as_str()))
+        .for_each(|feature| label.push_str(&format!(" +{}", feature.name)));
+    label
+}
+
+/// A line of the tree, like `core v0.1.0 (leaves/core) +fast -lm`
+fn describe(leaf: &Leaf, root: &Path) -> String {
+    let source = match &leaf.source {
+        Source::Path => match relative_path(&canonical_path(&leaf.path), root) {
+            path if path.as_os_str().is_empty() => ".".to_owned(),
+            path => path.display().to_string(),
+        },
+        Source::Git(checkout) => format!("{}#{}", checkout.source, &checkout.commit[..12]),
+        Source::Registry(release) => format!("registry {}", release.registry),
+    };
+
+    let mut line = label(leaf);
+    line.insert_str(
+        line.find(" +").unwrap_or(line.len()),
+        &format!(" ({})", source),
+    );
+    leaf.libraries
+        .iter()
+        .for_each(|library| line.push_str(&format!(" -l{}", library)));
+    line
+}
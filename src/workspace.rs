@@ -0,0 +1,236 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use glob::Pattern;
+
+use crate::{
+    cli::SelectionData,
+    config::{BuildConfig, Inheritable, Manifests, WorkspaceConfig},
+    diagnostic,
+    error::{Error, Result},
+    features::FeatureUnion,
+    git::GitCache,
+    graph::canonical_path,
+    registry::RegistryCache,
+};
+
+/// Leaves that are built together, sharing the target directory and build settings of the root
+pub struct Workspace {
+    pub root: PathBuf,
+    pub build: BuildConfig,
+    /// The directory of every member, including the root if it's a leaf itself
+    pub members: Vec<PathBuf>,
+    pub inheritable: Inheritable,
+}
+
+impl Workspace {
+    /// Looks for a tea.toml with a `[workspace]` in `path` and each of its parents. Returns None
+    /// if there isn't one, or `path` isn't one of its members
+    pub fn find(manifests: &mut Manifests, path: &Path) -> Result<Option<Self>> {
+        let current = canonical_path(path);
+
+        for directory in current.ancestors() {
+            let manifest = directory.join("tea.toml");
+            if !manifest.is_file() {
+                continue;
+            }
+
+            let document = manifests.document(directory)?;
+            let Some(config) = WorkspaceConfig::parse(document, directory)
+                .map_err(|message| Error::manifest(&manifest, message))?
+            else {
+                continue;
+            };
+            let build = BuildConfig::parse(document)
+                .map_err(|message| Error::manifest(&manifest, message))?;
+            let is_leaf = document.contains_key("package");
+
+            let workspace = Self {
+                root: directory.to_owned(),
+                build,
+                members: find_members(directory, &config, is_leaf)?,
+                inheritable: config.inheritable,
+            };
+            if directory == current || workspace.members.contains(&current) {
+                return Ok(Some(workspace));
+            }
+            return Ok(None);
+        }
+
+        Ok(None)
+    }
+
+    /// The member whose package is called `name`
+    fn member(&self, manifests: &mut Manifests, name: &str) -> Result<PathBuf> {
+        let mut names = Vec::new();
+        for member in &self.members {
+            let config = manifests.config(member)?;
+            if config.package.name == name {
+                return Ok(member.clone());
+            }
+            names.push(config.package.name);
+        }
+
+        let mut message = format!("no leaf named {} in the workspace", name);
+        if let Some(suggestion) = diagnostic::suggest(name, names.iter().map(String::as_str)) {
+            message.push_str(&format!(", did you mean {}?", suggestion));
+        }
+        Err(Error::Usage(message))
+    }
+}
+
+/// Expands the `members` globs of a workspace, skipping anything matched by `exclude`
+fn find_members(root: &Path, config: &WorkspaceConfig, is_leaf: bool) -> Result<Vec<PathBuf>> {
+    let manifest = root.join("tea.toml");
+    let pattern = |glob: &str| {
+        Pattern::new(&format!(
+            "{}/{}",
+            Pattern::escape(&root.to_string_lossy()),
+            glob
+        ))
+        .map_err(|error| Error::manifest(&manifest, format!("invalid glob {}: {}", glob, error)))
+    };
+    let exclude = config
+        .exclude
+        .iter()
+        .map(|glob| pattern(glob))
+        .collect::<Result<Vec<Pattern>>>()?;
+
+    let mut members = Vec::new();
+    if is_leaf {
+        members.push(root.to_owned());
+    }
+
+    for glob in &config.members {
+        let matches = glob::glob(pattern(glob)?.as_str())
+            .map_err(|error| {
+                Error::manifest(&manifest, format!("invalid glob {}: {}", glob, error))
+            })?
+            .filter_map(|path| path.ok())
+            .filter(|path| path.is_dir())
+            .filter(|path| !exclude.iter().any(|pattern| pattern.matches_path(path)))
+            .collect::<Vec<PathBuf>>();
+        if matches.is_empty() {
+            return Err(Error::manifest(
+                &manifest,
+                format!("workspace member {} doesn't match any directories", glob),
+            ));
+        }
+
+        for path in matches {
+            if !path.join("tea.toml").is_file() {
+                return Err(Error::manifest(
+                    &manifest,
+                    format!("workspace member {} has no tea.toml", path.display()),
+                ));
+            }
+            if !members.contains(&path) {
+                members.push(path);
+            }
+        }
+    }
+
+    Ok(members)
+}
+
+/// The leaves a command works on, and where their build settings and artifacts come from
+pub struct Project {
+    pub manifests: Manifests,
+    /// The workspace root, or the current leaf outside of a workspace
+    pub root: PathBuf,
+    pub build: BuildConfig,
+    /// Every leaf of the workspace, or only the current leaf outside of one
+    pub members: Vec<PathBuf>,
+    pub selected: Vec<PathBuf>,
+    pub git: GitCache,
+    pub registry: RegistryCache,
+    pub features: FeatureUnion,
+    /// Whether the features of each leaf are checked against its `[constraints]`, which isn't
+    /// done when every feature is enabled at once to write the lockfile
+    pub check_constraints: bool,
+    /// Whether the roots of the graph get their `[dev-dependencies]`, set when building tests
+    pub dev_dependencies: bool,
+}
+
+impl Project {
+    /// Selects the leaves named with `-p`, every member with `--workspace`, or otherwise the
+    /// current leaf. From a workspace root that isn't a leaf every member is selected
+    pub fn load(selection: &SelectionData) -> Result<Self> {
+        Self::load_in(Path::new(""), selection)
+    }
+
+    /// Like [`Project::load`], as if teapot was run in `directory`
+    pub fn load_in(directory: &Path, selection: &SelectionData) -> Result<Self> {
+        let mut manifests = Manifests::default();
+        let Some(workspace) = Workspace::find(&mut manifests, directory)? else {
+            let current = directory.to_owned();
+            let config = manifests.config(&current)?;
+            if let Some(name) = selection
+                .packages
+                .iter()
+                .find(|name| **name != config.package.name)
+            {
+                return Err(Error::Usage(format!(
+                    "no leaf named {}, the current leaf isn't part of a workspace",
+                    name
+                )));
+            }
+
+            return Ok(Self {
+                manifests,
+                root: current.clone(),
+                build: config.build,
+                members: vec![current.clone()],
+                selected: vec![current],
+                git: GitCache::default(),
+                registry: RegistryCache::default(),
+                features: FeatureUnion::default(),
+                check_constraints: true,
+                dev_dependencies: false,
+            });
+        };
+
+        let current = canonical_path(directory);
+        let selected = if selection.workspace {
+            workspace.members.clone()
+        } else if !selection.packages.is_empty() {
+            selection
+                .packages
+                .iter()
+                .map(|name| workspace.member(&mut manifests, name))
+                .collect::<Result<Vec<PathBuf>>>()?
+        } else if workspace.members.contains(&current) {
+            vec![current]
+        } else {
+            workspace.members.clone()
+        };
+        if selected.is_empty() {
+            return Err(Error::Usage("the workspace has no members".to_owned()));
+        }
+
+        Ok(Self {
+            manifests,
+            root: workspace.root,
+            build: workspace.build,
+            members: workspace.members,
+            selected,
+            git: GitCache::default(),
+            registry: RegistryCache::default(),
+            features: FeatureUnion::default(),
+            check_constraints: true,
+            dev_dependencies: false,
+        })
+    }
+
+    /// The registries configured in the project root, with their paths made absolute
+    pub fn registries(&self) -> HashMap<String, PathBuf> {
+        let root = canonical_path(&self.root);
+        self.build
+            .registries
+            .iter()
+            .map(|(name, path)| (name.clone(), root.join(path)))
+            .collect()
+    }
+}